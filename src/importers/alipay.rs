@@ -1,51 +1,95 @@
-use super::csv::{pick, Parser};
+use super::csv::{sniff, Parser, Record};
 use crate::lib::{Bean, Flow, Transaction};
-use anyhow;
-use csv::StringRecord;
 use fehler::throws;
-use std::path::PathBuf;
+use rust_decimal::Decimal;
 
-pub struct Alipay(StringRecord);
+const TRADE_ID: &str = "交易号";
+const DATE: &str = "交易创建时间";
+const PAYEE: &str = "交易对方";
+const COMMODITY: &str = "商品名称";
+const AMOUNT: &str = "金额（元）";
+const STATUS: &str = "交易状态";
+const FUND_STATUS: &str = "资金状态";
+
+/// Optional columns of purchases in foreign currencies, the amount column is then settled in CNY.
+const FOREIGN_AMOUNT: &str = "外币金额";
+const FOREIGN_CURRENCY: &str = "币种";
+
+const BANNER: &str = "支付宝交易记录明细查询";
+
+static COLUMNS: &[&str] = &[
+    TRADE_ID,
+    DATE,
+    PAYEE,
+    COMMODITY,
+    AMOUNT,
+    STATUS,
+    FUND_STATUS,
+];
+
+pub struct Alipay(Record);
 
 type Error = anyhow::Error;
 
 impl<'a> Alipay {
-    pub fn new(record: StringRecord) -> Self {
+    pub fn new(record: Record) -> Self {
         Self(record)
     }
 
     #[throws]
-    fn pick<F, T>(&'a self, name: &str, i: usize, transform: F) -> T
+    fn pick<F, T>(&'a self, name: &str, transform: F) -> T
     where
         F: Fn(&'a str) -> Option<T>,
     {
-        pick(&self.0, name, i, transform)?
+        self.0.pick(name, transform)?
     }
 
     fn default_transform(s: &str) -> Option<&str> {
         Some(s)
     }
+
+    #[throws]
+    fn cny_amount(&self) -> Decimal {
+        self.pick(AMOUNT, |s| s.parse().ok())?
+    }
+
+    fn foreign_amount(&self) -> Option<(Decimal, &str)> {
+        self.0.foreign_amount(FOREIGN_AMOUNT, FOREIGN_CURRENCY)
+    }
 }
 
 impl Transaction for Alipay {
     #[throws]
     fn date(&self) -> &str {
-        self.pick("date", 2, |s| s.split_whitespace().next())?
+        self.pick(DATE, |s| s.split_whitespace().next())?
+    }
+
+    #[throws]
+    fn time(&self) -> &str {
+        self.pick(DATE, |s| Some(s.split_whitespace().nth(1).unwrap_or("")))?
     }
 
     #[throws]
     fn payee(&self) -> &str {
-        self.pick("payee", 7, Self::default_transform)?
+        self.pick(PAYEE, Self::default_transform)?
     }
 
     #[throws]
     fn narration(&self) -> &str {
-        self.pick("narration", 8, Self::default_transform)?
+        self.pick(COMMODITY, Self::default_transform)?
     }
 
     #[throws]
-    fn amount(&self) -> f32 {
-        let amount: f32 = self.pick("amount", 9, |s| s.parse().ok())?;
+    fn id(&self) -> &str {
+        self.pick(TRADE_ID, Self::default_transform)?
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        let amount = match self.foreign_amount() {
+            Some((foreign, _)) => foreign,
+            None => self.cny_amount()?,
+        };
         match self.flow()? {
             Flow::Income => -amount,
             _ => amount,
@@ -53,27 +97,49 @@ impl Transaction for Alipay {
     }
 
     #[throws]
-    fn flow(&self) -> Flow {
-        let flow = self.pick("flow", 15, Self::default_transform)?;
+    fn currency(&self) -> &str {
+        self.foreign_amount()
+            .map_or("CNY", |(_, currency)| currency)
+    }
+
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        match self.foreign_amount() {
+            Some(_) => Some((self.cny_amount()?, "CNY")),
+            None => None,
+        }
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        let flow = self.pick(FUND_STATUS, Self::default_transform)?;
         Flow::from(flow)
     }
 
+    fn source(&self) -> &str {
+        "alipay"
+    }
+
     fn display(&self) -> String {
         format!("{:?}", self.0)
     }
 
     fn is_valid(&self) -> bool {
-        self.pick("kind", 11, Self::default_transform)
+        self.pick(STATUS, Self::default_transform)
             .map(|v| v != "交易关闭")
             .unwrap_or(false)
     }
 }
 
+pub fn is_match(contents: &str) -> bool {
+    sniff(contents, BANNER, COLUMNS)
+}
+
 #[throws]
-pub fn import(input: PathBuf, edit: bool) -> String {
-    let parser = Parser::new(input, 4, edit);
+pub fn import(contents: &str) -> Bean<'static> {
+    let parser = Parser::new(COLUMNS);
     let bean = Bean::new("Assets:Alipay");
-    parser.output(bean, Alipay::new)?
+    parser.parse_into(contents, bean, Alipay::new)?
 }
 
 #[cfg(test)]
@@ -83,6 +149,25 @@ mod tests {
     use crate::test_helpers::gen_record;
     use fehler::throws;
 
+    static HEADERS: &[&str] = &[
+        "交易号",
+        "商户订单号",
+        "交易创建时间",
+        "付款时间",
+        "最近修改时间",
+        "交易来源地",
+        "类型",
+        "交易对方",
+        "商品名称",
+        "金额（元）",
+        "收/支",
+        "交易状态",
+        "服务费（元）",
+        "成功退款（元）",
+        "备注",
+        "资金状态",
+    ];
+
     #[derive(Default)]
     pub struct Trans<'a> {
         create_date: &'a str,
@@ -104,7 +189,7 @@ mod tests {
     }
     impl<'a> Trans<'a> {
         pub fn as_string(&self) -> String {
-            vec![
+            [
                 self.trade_id,
                 self.store_id,
                 self.create_date,
@@ -135,7 +220,7 @@ mod tests {
             create_date: "2020-03-30 18:46:56",
             ..Trans::default()
         };
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let transaction = Alipay::new(r);
         assert_eq!(transaction.date()?, "2020-03-30")
     }
@@ -143,7 +228,7 @@ mod tests {
     #[test]
     #[throws]
     fn it_mark_closed_transanction_invalid() {
-        let r = gen_record("xxx	,Cxxx	,2020-04-08 14:56:53 ,                    ,2020-04-23 14:57:12 ,其他（包括阿里巴巴和外部商家）,即时到账交易          ,companyname    ,订单号：Cxxx,100.00,        ,交易关闭    ,0.00     ,0.00     ,                    ,         ,")?;
+        let r = gen_record(HEADERS, "xxx	,Cxxx	,2020-04-08 14:56:53 ,                    ,2020-04-23 14:57:12 ,其他（包括阿里巴巴和外部商家）,即时到账交易          ,companyname    ,订单号：Cxxx,100.00,        ,交易关闭    ,0.00     ,0.00     ,                    ,         ,")?;
         let transaction = Alipay::new(r);
         assert!(!transaction.is_valid());
     }
@@ -151,8 +236,57 @@ mod tests {
     #[test]
     #[throws]
     fn it_handle_yuebao() {
-        let r = gen_record("20210625322930531831 	,                    	,2021-06-25 05:59:17 ,                    ,2021-06-25 05:59:17 ,支付宝网站     ,即时到账交易          ,华安基金管理有限公司      ,余额宝-2021.06.24-收益发放 ,0.52    ,        ,交易成功    ,0.00     ,0.00     ,                    ,已收入      ,")?;
+        let r = gen_record(HEADERS, "20210625322930531831 	,                    	,2021-06-25 05:59:17 ,                    ,2021-06-25 05:59:17 ,支付宝网站     ,即时到账交易          ,华安基金管理有限公司      ,余额宝-2021.06.24-收益发放 ,0.52    ,        ,交易成功    ,0.00     ,0.00     ,                    ,已收入      ,")?;
         let transaction = Alipay::new(r);
         assert_eq!(transaction.flow()?, Flow::Income);
     }
+
+    #[test]
+    #[throws]
+    fn it_keep_amount_precision() {
+        let t = Trans {
+            amount: "1234567.89",
+            fund_status: "已支出",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let transaction = Alipay::new(r);
+        assert_eq!(transaction.amount()?.to_string(), "1234567.89");
+    }
+
+    #[test]
+    #[throws]
+    fn get_trade_id() {
+        let t = Trans {
+            trade_id: "2021062422001",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let transaction = Alipay::new(r);
+        assert_eq!(transaction.id()?, "2021062422001");
+    }
+
+    #[test]
+    #[throws]
+    fn get_foreign_amount() {
+        let headers = [HEADERS, &["外币金额", "币种"]].concat();
+        let t = Trans {
+            amount: "86.40",
+            fund_status: "已支出",
+            ..Trans::default()
+        };
+        let r = gen_record(&headers, &format!("{},12.00,USD", t.as_string()))?;
+        let transaction = Alipay::new(r);
+        assert_eq!(transaction.amount()?.to_string(), "12.00");
+        assert_eq!(transaction.currency()?, "USD");
+        assert_eq!(
+            transaction.price()?.map(|(p, c)| (p.to_string(), c)),
+            Some(("86.40".to_owned(), "CNY"))
+        );
+        let r = gen_record(&headers, &format!("{},86.40,CNY", t.as_string()))?;
+        let transaction = Alipay::new(r);
+        assert_eq!(transaction.amount()?.to_string(), "86.40");
+        assert_eq!(transaction.currency()?, "CNY");
+        assert_eq!(transaction.price()?, None);
+    }
 }
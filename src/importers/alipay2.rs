@@ -0,0 +1,292 @@
+use super::csv::{sniff, Parser, Record};
+use crate::lib::{Bean, Flow, Transaction};
+use fehler::throws;
+use rust_decimal::Decimal;
+
+const DATE: &str = "交易时间";
+const CATEGORY: &str = "交易分类";
+const PAYEE: &str = "交易对方";
+const COMMODITY: &str = "商品说明";
+const FLOW: &str = "收/支";
+const AMOUNT: &str = "金额";
+const FUND: &str = "收/付款方式";
+const STATUS: &str = "交易状态";
+const TRADE_ID: &str = "交易订单号";
+
+/// Optional columns of purchases in foreign currencies, the amount column is then settled in CNY.
+const FOREIGN_AMOUNT: &str = "外币金额";
+const FOREIGN_CURRENCY: &str = "币种";
+
+const BANNER: &str = "电子客户回单";
+
+static COLUMNS: &[&str] = &[
+    DATE, CATEGORY, PAYEE, COMMODITY, FLOW, AMOUNT, FUND, STATUS, TRADE_ID,
+];
+
+/// Alipay 交易明细 statement, exported since 2023.
+pub struct Alipay2(Record);
+
+type Error = anyhow::Error;
+
+impl<'a> Alipay2 {
+    pub fn new(record: Record) -> Self {
+        Self(record)
+    }
+
+    #[throws]
+    fn pick<F, T>(&'a self, name: &str, transform: F) -> T
+    where
+        F: Fn(&'a str) -> Option<T>,
+    {
+        self.0.pick(name, transform)?
+    }
+
+    fn default_transform(s: &str) -> Option<&str> {
+        Some(s)
+    }
+
+    #[throws]
+    fn cny_amount(&self) -> Decimal {
+        self.pick(AMOUNT, |s| s.parse().ok())?
+    }
+
+    fn foreign_amount(&self) -> Option<(Decimal, &str)> {
+        self.0.foreign_amount(FOREIGN_AMOUNT, FOREIGN_CURRENCY)
+    }
+}
+
+impl Transaction for Alipay2 {
+    #[throws]
+    fn date(&self) -> &str {
+        self.pick(DATE, |s| s.split_whitespace().next())?
+    }
+
+    #[throws]
+    fn time(&self) -> &str {
+        self.pick(DATE, |s| Some(s.split_whitespace().nth(1).unwrap_or("")))?
+    }
+
+    #[throws]
+    fn payee(&self) -> &str {
+        self.pick(PAYEE, Self::default_transform)?
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.pick(FUND, Self::default_transform)?
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        self.pick(COMMODITY, Self::default_transform)?
+    }
+
+    #[throws]
+    fn id(&self) -> &str {
+        self.pick(TRADE_ID, Self::default_transform)?
+    }
+
+    #[throws]
+    fn metadata(&self) -> Vec<(&str, &str)> {
+        let mut meta = vec![];
+        let category = self.pick(CATEGORY, Self::default_transform)?;
+        if !category.is_empty() {
+            meta.push(("category", category));
+        }
+        if let Flow::Unknown(s) = self.flow()? {
+            meta.push(("unknown_flow", s));
+        }
+        meta
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        let amount = match self.foreign_amount() {
+            Some((foreign, _)) => foreign,
+            None => self.cny_amount()?,
+        };
+        match self.flow()? {
+            Flow::Income => -amount,
+            _ => amount,
+        }
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        self.foreign_amount()
+            .map_or("CNY", |(_, currency)| currency)
+    }
+
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        match self.foreign_amount() {
+            Some(_) => Some((self.cny_amount()?, "CNY")),
+            None => None,
+        }
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        let flow = self.pick(FLOW, Self::default_transform)?;
+        Flow::from(flow)
+    }
+
+    fn source(&self) -> &str {
+        "alipay2"
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.0)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.pick(STATUS, Self::default_transform)
+            .map(|v| v != "交易关闭")
+            .unwrap_or(false)
+    }
+}
+
+pub fn is_match(contents: &str) -> bool {
+    sniff(contents, BANNER, COLUMNS)
+}
+
+#[throws]
+pub fn import(contents: &str) -> Bean<'static> {
+    let parser = Parser::new(COLUMNS);
+    let bean = Bean::new("Assets:Alipay");
+    parser.parse_into(contents, bean, Alipay2::new)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Alipay2;
+    use crate::lib::{Flow, Transaction};
+    use crate::test_helpers::gen_record;
+    use fehler::throws;
+
+    static HEADERS: &[&str] = &[
+        "交易时间",
+        "交易分类",
+        "交易对方",
+        "对方账号",
+        "商品说明",
+        "收/支",
+        "金额",
+        "收/付款方式",
+        "交易状态",
+        "交易订单号",
+        "商家订单号",
+        "备注",
+    ];
+
+    #[derive(Default)]
+    pub struct Trans<'a> {
+        date: &'a str,
+        category: &'a str,
+        payee: &'a str,
+        payee_account: &'a str,
+        commodity: &'a str,
+        flow: &'a str,
+        amount: &'a str,
+        fund: &'a str,
+        status: &'a str,
+        trade_id: &'a str,
+        store_id: &'a str,
+        remark: &'a str,
+    }
+    impl<'a> Trans<'a> {
+        pub fn as_string(&self) -> String {
+            [
+                self.date,
+                self.category,
+                self.payee,
+                self.payee_account,
+                self.commodity,
+                self.flow,
+                self.amount,
+                self.fund,
+                self.status,
+                self.trade_id,
+                self.store_id,
+                self.remark,
+            ]
+            .join(",")
+        }
+    }
+
+    type Error = anyhow::Error;
+
+    #[test]
+    #[throws]
+    fn get_date_and_fund() {
+        let t = Trans {
+            date: "2023-03-30 18:46:56",
+            fund: "招商银行储蓄卡(1234)",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let transaction = Alipay2::new(r);
+        assert_eq!(transaction.date()?, "2023-03-30");
+        assert_eq!(transaction.fund()?, "招商银行储蓄卡(1234)");
+    }
+
+    #[test]
+    #[throws]
+    fn get_income_amount() {
+        let t = Trans {
+            flow: "收入",
+            amount: "0.52",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let transaction = Alipay2::new(r);
+        assert_eq!(transaction.flow()?, Flow::Income);
+        assert_eq!(transaction.amount()?.to_string(), "-0.52");
+    }
+
+    #[test]
+    #[throws]
+    fn add_category_to_metadata() {
+        let t = Trans {
+            category: "餐饮美食",
+            flow: "不计收支",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let transaction = Alipay2::new(r);
+        assert_eq!(
+            transaction.metadata()?,
+            vec![("category", "餐饮美食"), ("unknown_flow", "不计收支")]
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_mark_closed_transanction_invalid() {
+        let t = Trans {
+            status: "交易关闭",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        assert!(!Alipay2::new(r).is_valid());
+    }
+
+    #[test]
+    #[throws]
+    fn get_foreign_refund() {
+        let headers = [HEADERS, &["外币金额", "币种"]].concat();
+        let t = Trans {
+            flow: "收入",
+            amount: "43.20",
+            ..Trans::default()
+        };
+        let r = gen_record(&headers, &format!("{},6.00,EUR", t.as_string()))?;
+        let transaction = Alipay2::new(r);
+        assert_eq!(transaction.amount()?.to_string(), "-6.00");
+        assert_eq!(transaction.currency()?, "EUR");
+        assert_eq!(
+            transaction.price()?.map(|(p, c)| (p.to_string(), c)),
+            Some(("43.20".to_owned(), "CNY"))
+        );
+    }
+}
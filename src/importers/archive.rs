@@ -0,0 +1,131 @@
+use anyhow::anyhow;
+use fehler::throws;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+type Error = anyhow::Error;
+
+static STATEMENT_EXTENSIONS: &[&str] = &["csv", "xlsx"];
+
+/// Archives created on macOS list the resource forks like `__MACOSX/._statement.csv` before the
+/// files, they are skipped.
+fn is_statement(name: &str) -> bool {
+    let path = Path::new(name);
+    let resource_fork = path.components().any(|c| c.as_os_str() == "__MACOSX")
+        || path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("._"));
+    !resource_fork
+        && path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                STATEMENT_EXTENSIONS
+                    .iter()
+                    .any(|e| ext.eq_ignore_ascii_case(e))
+            })
+}
+
+/// Extract the first CSV or xlsx statement from a zip archive in memory, returns its name and
+/// contents. The password is prompted for if the statement is encrypted and none is given.
+#[throws]
+pub fn extract(bytes: &[u8], password: Option<&str>) -> (String, Vec<u8>) {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let index = (0..archive.len())
+        .find(|&i| {
+            archive
+                .by_index_raw(i)
+                .map(|file| is_statement(file.name()))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow!("No CSV or xlsx statement found in the archive"))?;
+    let encrypted = matches!(
+        archive.by_index(index),
+        Err(ZipError::UnsupportedArchive(message)) if message == ZipError::PASSWORD_REQUIRED
+    );
+    let mut file = if encrypted {
+        let password = match password {
+            Some(password) => password.to_owned(),
+            None => rpassword::prompt_password("Password of the archive: ")?,
+        };
+        archive
+            .by_index_decrypt(index, password.as_bytes())?
+            .map_err(|_| anyhow!("Invalid password of the archive"))?
+    } else {
+        archive.by_index(index)?
+    };
+    let name = file.name().to_owned();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    (name, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, is_statement};
+    use fehler::throws;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    type Error = anyhow::Error;
+
+    /// `__MACOSX/._statement.csv` and `statement.csv` encrypted with ZipCrypto by `zip -P secret`.
+    static ENCRYPTED: &[u8] = include_bytes!("testdata/encrypted.zip");
+
+    #[throws]
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        for (name, contents) in files {
+            writer.start_file(*name, options)?;
+            writer.write_all(contents.as_bytes())?;
+        }
+        writer.finish()?.into_inner()
+    }
+
+    #[test]
+    fn it_find_statement() {
+        assert!(is_statement("微信支付账单(20200301-20200331).csv"));
+        assert!(is_statement("bill/statement.XLSX"));
+        assert!(!is_statement("__MACOSX/._statement.csv"));
+        assert!(!is_statement("bill/._statement.csv"));
+    }
+
+    #[test]
+    #[throws]
+    fn it_extract_first_statement() {
+        let bytes = archive(&[
+            ("__MACOSX/bill/._statement.csv", "resource fork"),
+            ("bill/statement.csv", "交易时间,交易类型"),
+            ("bill/statement.xlsx", "xlsx"),
+        ])?;
+        let (name, contents) = extract(&bytes, None)?;
+        assert_eq!(name, "bill/statement.csv");
+        assert_eq!(contents, "交易时间,交易类型".as_bytes());
+    }
+
+    #[test]
+    #[throws]
+    fn it_extract_without_statement() {
+        let bytes = archive(&[("readme.txt", "readme")])?;
+        let error = extract(&bytes, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No CSV or xlsx statement found in the archive"
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_extract_encrypted_statement() {
+        let (name, contents) = extract(ENCRYPTED, Some("secret"))?;
+        assert_eq!(name, "statement.csv");
+        assert_eq!(contents, "交易时间,交易类型\n".as_bytes());
+        let error = extract(ENCRYPTED, Some("wrong")).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid password of the archive");
+    }
+}
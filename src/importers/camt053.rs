@@ -0,0 +1,381 @@
+use super::markup::scan;
+use crate::lib::{Balance, Bean, Flow, Reject, Transaction};
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use fehler::{throw, throws};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+type Error = anyhow::Error;
+
+/// Leaf values of an aggregate, keyed by their paths relative to it like `BookgDt/Dt`. Amounts
+/// have their currencies keyed as `Amt@Ccy`.
+type Fields = HashMap<String, String>;
+
+static COUNTERPARTY_CREDITOR: &[&str] = &[
+    "NtryDtls/TxDtls/RltdPties/Cdtr/Nm",
+    "NtryDtls/TxDtls/RltdPties/Cdtr/Pty/Nm",
+];
+static COUNTERPARTY_DEBTOR: &[&str] = &[
+    "NtryDtls/TxDtls/RltdPties/Dbtr/Nm",
+    "NtryDtls/TxDtls/RltdPties/Dbtr/Pty/Nm",
+];
+
+fn find<'a>(fields: &'a Fields, keys: &[&str]) -> Option<&'a str> {
+    keys.iter()
+        .find_map(|key| fields.get(*key))
+        .map(|s| s.as_str())
+        .filter(|s| !s.is_empty())
+}
+
+/// Date of a `Dt` or `DtTm` element.
+fn find_date<'a>(fields: &'a Fields, aggregate: &str) -> Option<&'a str> {
+    let date = fields.get(&format!("{}/Dt", aggregate));
+    let datetime = fields.get(&format!("{}/DtTm", aggregate));
+    date.or(datetime).and_then(|s| s.get(..10))
+}
+
+/// Time of a `DtTm` element like `2024-03-10T10:00:00+01:00`, the offset is dropped.
+fn find_time<'a>(fields: &'a Fields, aggregate: &str) -> Option<&'a str> {
+    fields
+        .get(&format!("{}/DtTm", aggregate))
+        .and_then(|s| s.get(11..19))
+}
+
+/// An `Ntry` of an ISO 20022 camt.053 bank to customer statement.
+pub struct Camt053 {
+    fields: Fields,
+    account: String,
+}
+
+impl Camt053 {
+    fn new(fields: Fields, account: &str) -> Self {
+        Self {
+            fields,
+            account: account.to_owned(),
+        }
+    }
+
+    #[throws]
+    fn pick(&self, key: &str) -> &str {
+        find(&self.fields, &[key]).ok_or_else(|| anyhow!("Missing {} in the entry", key))?
+    }
+
+    #[throws]
+    fn is_debit(&self) -> bool {
+        match self.pick("CdtDbtInd")? {
+            "DBIT" => true,
+            "CRDT" => false,
+            indicator => throw!(anyhow!("Invalid CdtDbtInd: {}", indicator)),
+        }
+    }
+}
+
+impl Transaction for Camt053 {
+    #[throws]
+    fn date(&self) -> &str {
+        find_date(&self.fields, "BookgDt")
+            .or_else(|| find_date(&self.fields, "ValDt"))
+            .ok_or_else(|| anyhow!("Missing BookgDt in the entry"))?
+    }
+
+    /// Time of the date the entry is booked on, if given.
+    #[throws]
+    fn time(&self) -> &str {
+        let aggregate = match find_date(&self.fields, "BookgDt") {
+            Some(_) => "BookgDt",
+            None => "ValDt",
+        };
+        find_time(&self.fields, aggregate).unwrap_or("")
+    }
+
+    /// The creditor for debits, the debtor for credits.
+    #[throws]
+    fn payee(&self) -> &str {
+        let keys = if self.is_debit()? {
+            COUNTERPARTY_CREDITOR
+        } else {
+            COUNTERPARTY_DEBTOR
+        };
+        find(&self.fields, keys)
+            .or_else(|| find(&self.fields, &["AddtlNtryInf"]))
+            .unwrap_or("")
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.account.as_str()
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        find(&self.fields, &["NtryDtls/TxDtls/RmtInf/Ustrd"])
+            .or_else(|| find(&self.fields, &["AddtlNtryInf"]))
+            .unwrap_or("")
+    }
+
+    #[throws]
+    fn id(&self) -> &str {
+        find(
+            &self.fields,
+            &["AcctSvcrRef", "NtryDtls/TxDtls/Refs/AcctSvcrRef"],
+        )
+        .unwrap_or("")
+    }
+
+    #[throws]
+    fn metadata(&self) -> Vec<(&str, &str)> {
+        let mut meta = vec![];
+        if let Some(date) = find_date(&self.fields, "ValDt") {
+            meta.push(("value_date", date));
+        }
+        meta
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        let amount = self.pick("Amt")?;
+        let amount = amount
+            .parse::<Decimal>()
+            .map_err(|_| anyhow!("Invalid Amt: {}", amount))?;
+        if self.is_debit()? {
+            amount
+        } else {
+            -amount
+        }
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        self.pick("Amt@Ccy")?
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        if self.is_debit()? {
+            Flow::Expense
+        } else {
+            Flow::Income
+        }
+    }
+
+    /// Pending and informational entries aren't booked yet.
+    fn is_valid(&self) -> bool {
+        find(&self.fields, &["Sts", "Sts/Cd"]).is_none_or(|status| status == "BOOK")
+    }
+
+    fn source(&self) -> &str {
+        "camt053"
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.fields)
+    }
+}
+
+/// Opening (`OPBD`) or closing (`CLBD`) balance of the statement as its code, date, amount and
+/// currency, other balances are ignored.
+#[throws]
+fn statement_balance(fields: &Fields) -> Option<(&str, NaiveDate, Decimal, &str)> {
+    let code = match find(fields, &["Tp/CdOrPrtry/Cd"]) {
+        Some(code) if code == "OPBD" || code == "CLBD" => code,
+        _ => return None,
+    };
+    let date = find_date(fields, "Dt").ok_or_else(|| anyhow!("Missing Dt of the balance"))?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+    let amount = find(fields, &["Amt"]).ok_or_else(|| anyhow!("Missing Amt of the balance"))?;
+    let mut amount = amount
+        .parse::<Decimal>()
+        .map_err(|_| anyhow!("Invalid Amt: {}", amount))?;
+    if find(fields, &["CdtDbtInd"]) == Some("DBIT") {
+        amount = -amount;
+    }
+    let currency =
+        find(fields, &["Amt@Ccy"]).ok_or_else(|| anyhow!("Missing Ccy of the balance"))?;
+    Some((code, date, amount, currency))
+}
+
+pub fn is_match(contents: &str) -> bool {
+    contents.contains("camt.053") || contents.contains("<BkToCstmrStmt>")
+}
+
+#[throws]
+pub fn import(contents: &str) -> Bean<'static> {
+    let mut bean = Bean::new("Assets:Bank");
+    let lines = contents.lines().collect::<Vec<_>>();
+    let raw = |start: usize, end: usize| {
+        lines[start - 1..end]
+            .iter()
+            .map(|s| s.trim())
+            .collect::<String>()
+    };
+    let mut path: Vec<&str> = vec![];
+    let mut account = String::new();
+    // The aggregate being collected, `Ntry` or `Bal`, with its depth and line
+    let mut aggregate: Option<(&str, usize, usize, Fields)> = None;
+    // Opening and closing balances of the statement, and the movement of its entries
+    let mut balances: (Option<Decimal>, Option<Decimal>) = (None, None);
+    let mut movement = Decimal::ZERO;
+    for element in scan(contents) {
+        if element.closing {
+            if let Some(depth) = path.iter().rposition(|tag| *tag == element.tag) {
+                path.truncate(depth);
+            }
+            if element.tag == "Stmt" {
+                if let (Some(opening), Some(closing)) = balances {
+                    let what = format!("Closing balance of {}", account);
+                    bean.verify(&what, closing, opening + movement);
+                }
+                balances = (None, None);
+                movement = Decimal::ZERO;
+            }
+            match aggregate.take() {
+                Some((tag, depth, line, fields)) if depth == path.len() => match tag {
+                    "Ntry" => {
+                        let transaction = Camt053::new(fields, &account);
+                        if let Some(t) = bean.add_row(line, &raw(line, element.line), transaction) {
+                            movement -= t.amount().unwrap_or_default();
+                        }
+                    }
+                    _ => match statement_balance(&fields) {
+                        Ok(Some(("OPBD", _, amount, _))) => balances.0 = Some(amount),
+                        Ok(Some((_, date, amount, currency))) => {
+                            balances.1 = Some(amount);
+                            bean.add_balance(Balance::closing(date, &account, amount, currency));
+                        }
+                        Ok(None) => {}
+                        Err(e) => bean.reject(Reject {
+                            line,
+                            raw: raw(line, element.line),
+                            reason: format!("{:#}", e),
+                        }),
+                    },
+                },
+                other => aggregate = other,
+            }
+            continue;
+        }
+        path.push(element.tag);
+        match &mut aggregate {
+            Some((_, depth, _, fields)) => {
+                let key = path[*depth + 1..].join("/");
+                if let Some(currency) = element.attr("Ccy") {
+                    fields
+                        .entry(format!("{}@Ccy", key))
+                        .or_insert_with(|| currency.to_owned());
+                }
+                if !element.text.is_empty() {
+                    match fields.get_mut(&key) {
+                        // Unstructured remittance information may be split into several lines
+                        Some(value) if element.tag == "Ustrd" => {
+                            value.push(' ');
+                            value.push_str(&element.text);
+                        }
+                        Some(_) => {}
+                        None => {
+                            fields.insert(key, element.text);
+                        }
+                    }
+                }
+            }
+            None if matches!(element.tag, "Ntry" | "Bal") => {
+                aggregate = Some((element.tag, path.len() - 1, element.line, Fields::new()));
+            }
+            None if path.ends_with(&["Stmt", "Acct", "Id", "IBAN"])
+                || path.ends_with(&["Stmt", "Acct", "Id", "Othr", "Id"]) =>
+            {
+                account = element.text;
+            }
+            None => {}
+        }
+    }
+    bean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::import;
+    use crate::lib::{Format, Rules};
+    use fehler::throws;
+
+    type Error = anyhow::Error;
+
+    static CAMT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+  <BkToCstmrStmt>
+    <Stmt>
+      <Acct><Id><IBAN>DE89370400440532013000</IBAN></Id><Ccy>EUR</Ccy></Acct>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>OPBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">1000.00</Amt><CdtDbtInd>CRDT</CdtDbtInd>
+        <Dt><Dt>2024-03-01</Dt></Dt>
+      </Bal>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>CLBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">1487.50</Amt><CdtDbtInd>CRDT</CdtDbtInd>
+        <Dt><Dt>2024-03-31</Dt></Dt>
+      </Bal>
+      <Ntry>
+        <Amt Ccy="EUR">12.50</Amt><CdtDbtInd>DBIT</CdtDbtInd><Sts>BOOK</Sts>
+        <BookgDt><Dt>2024-03-04</Dt></BookgDt><ValDt><Dt>2024-03-05</Dt></ValDt>
+        <AcctSvcrRef>REF1</AcctSvcrRef>
+        <NtryDtls><TxDtls>
+          <RltdPties><Dbtr><Nm>ACME GmbH</Nm></Dbtr><Cdtr><Nm>Stadtwerke</Nm></Cdtr></RltdPties>
+          <RmtInf><Ustrd>Strom Maerz</Ustrd><Ustrd>Kunde 42</Ustrd></RmtInf>
+        </TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">500.00</Amt><CdtDbtInd>CRDT</CdtDbtInd><Sts>BOOK</Sts>
+        <BookgDt><DtTm>2024-03-10T10:00:00.000+01:00</DtTm></BookgDt>
+        <AcctSvcrRef>REF2</AcctSvcrRef>
+        <NtryDtls><TxDtls>
+          <RltdPties><Dbtr><Nm>Customer &amp; Co</Nm></Dbtr></RltdPties>
+        </TxDtls></NtryDtls>
+        <AddtlNtryInf>Invoice 7</AddtlNtryInf>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">1.00</Amt><CdtDbtInd>DBIT</CdtDbtInd><Sts>PDNG</Sts>
+        <BookgDt><Dt>2024-03-31</Dt></BookgDt>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>
+"#;
+
+    #[test]
+    #[throws]
+    fn it_import_entries_and_closing_balance() {
+        let bean = import(CAMT)?;
+        assert!(bean.rejects().is_empty());
+        assert!(bean.warnings().is_empty());
+        let rules = Rules::from_str("[fund]\n\"DE89370400440532013000\" = \"Assets:Bank:Giro\"")?;
+        assert_eq!(
+            bean.output_with_rules(rules, Format::Beancount)?,
+            r#"2024-03-04 ! "Stadtwerke" "Strom Maerz Kunde 42"
+  trade_id: "REF1"
+  value_date: "2024-03-05"
+  Expenses:Unknown 12.50 EUR
+  Assets:Bank:Giro
+2024-03-10 ! "Customer & Co" "Invoice 7"
+  trade_id: "REF2"
+  time: "10:00:00"
+  Income:Unknown -500.00 EUR
+  Assets:Bank:Giro
+2024-04-01 balance Assets:Bank:Giro 1487.50 EUR
+"#
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_reject_malformed_entries() {
+        let camt = CAMT.replace("<CdtDbtInd>DBIT</CdtDbtInd><Sts>BOOK", "<Sts>BOOK");
+        let bean = import(&camt)?;
+        assert_eq!(bean.rejects().len(), 1);
+        assert_eq!(bean.rejects()[0].line, 16);
+        assert_eq!(
+            bean.warnings(),
+            ["Closing balance of DE89370400440532013000 is 1487.50 in the statement, but 1500.00 imported"]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use super::csv::{parse_amount, parse_date, sniff, Parser, Record};
+use crate::lib::{Bean, Flow, Transaction};
+use anyhow::anyhow;
+use fehler::throws;
+use rust_decimal::Decimal;
+
+const DATE: &str = "交易日";
+const POSTING_DATE: &str = "记账日";
+const DESCRIPTION: &str = "交易摘要";
+const AMOUNT: &str = "人民币金额";
+const CARD: &str = "卡号末四位";
+const FOREIGN_AMOUNT: &str = "交易地金额";
+
+const BANNER: &str = "招商银行信用卡";
+
+static COLUMNS: &[&str] = &[DATE, DESCRIPTION, AMOUNT, CARD];
+
+static DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y/%m/%d", "%Y%m%d"];
+
+/// China Merchants Bank credit card statement.
+pub struct Cmb {
+    record: Record,
+    date: Option<String>,
+    posting_date: Option<String>,
+}
+
+type Error = anyhow::Error;
+
+impl<'a> Cmb {
+    pub fn new(record: Record) -> Self {
+        let date = record.get(DATE).and_then(|s| parse_date(s, DATE_FORMATS));
+        let posting_date = record
+            .get(POSTING_DATE)
+            .and_then(|s| parse_date(s, DATE_FORMATS));
+        Self {
+            record,
+            date,
+            posting_date,
+        }
+    }
+
+    #[throws]
+    fn pick<F, T>(&'a self, name: &str, transform: F) -> T
+    where
+        F: Fn(&'a str) -> Option<T>,
+    {
+        self.record.pick(name, transform)?
+    }
+
+    fn default_transform(s: &str) -> Option<&str> {
+        Some(s)
+    }
+
+    #[throws]
+    fn rmb_amount(&self) -> Decimal {
+        self.pick(AMOUNT, parse_amount)?
+    }
+
+    /// Amount in the currency of the transaction place, like `12.00(USD)` or `USD 12.00`.
+    fn foreign_amount(&self) -> Option<(Decimal, &str)> {
+        let field = self.record.get(FOREIGN_AMOUNT)?;
+        let start = field.find(|c: char| c.is_ascii_uppercase())?;
+        let currency = &field[start..];
+        let currency = &currency[..currency
+            .find(|c: char| !c.is_ascii_uppercase())
+            .unwrap_or(currency.len())];
+        if currency.len() != 3 || currency == "CNY" {
+            return None;
+        }
+        let amount = field
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '.')
+            .collect::<String>();
+        Some((amount.parse().ok()?, currency))
+    }
+}
+
+impl Transaction for Cmb {
+    #[throws]
+    fn date(&self) -> &str {
+        match &self.date {
+            Some(date) => date,
+            None => self.pick(DATE, Self::default_transform)?,
+        }
+    }
+
+    #[throws]
+    fn payee(&self) -> &str {
+        self.pick(DESCRIPTION, Self::default_transform)?
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.pick(CARD, Self::default_transform)?
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        ""
+    }
+
+    /// The posting date in the same format as the date.
+    #[throws]
+    fn metadata(&self) -> Vec<(&str, &str)> {
+        let mut meta = vec![];
+        if let Some(date) = self.record.get(POSTING_DATE).filter(|s| !s.is_empty()) {
+            let date = self
+                .posting_date
+                .as_deref()
+                .ok_or_else(|| anyhow!("Invalid {}: {}", POSTING_DATE, date))?;
+            meta.push(("posting_date", date));
+        }
+        meta
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        let amount = self.rmb_amount()?;
+        match self.foreign_amount() {
+            Some((foreign, _)) if amount.is_sign_negative() => -foreign,
+            Some((foreign, _)) => foreign,
+            None => amount,
+        }
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        self.foreign_amount()
+            .map_or("CNY", |(_, currency)| currency)
+    }
+
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        match self.foreign_amount() {
+            Some(_) => Some((self.rmb_amount()?.abs(), "CNY")),
+            None => None,
+        }
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        if self.rmb_amount()?.is_sign_negative() {
+            Flow::Income
+        } else {
+            Flow::Expense
+        }
+    }
+
+    fn source(&self) -> &str {
+        "cmb"
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.record)
+    }
+}
+
+pub fn is_match(contents: &str) -> bool {
+    sniff(contents, BANNER, COLUMNS)
+}
+
+#[throws]
+pub fn import(contents: &str) -> Bean<'static> {
+    let parser = Parser::new(COLUMNS);
+    let bean = Bean::new("Liabilities:CMB:CreditCard");
+    parser.parse_into(contents, bean, Cmb::new)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cmb;
+    use crate::lib::{Flow, Transaction};
+    use crate::test_helpers::gen_record;
+    use fehler::throws;
+
+    static HEADERS: &[&str] = &[
+        "交易日",
+        "记账日",
+        "交易摘要",
+        "人民币金额",
+        "卡号末四位",
+        "交易地金额",
+    ];
+
+    type Error = anyhow::Error;
+
+    #[test]
+    #[throws]
+    fn get_rmb_transaction() {
+        let r = gen_record(
+            HEADERS,
+            "2024/03/15,2024/03/16,财付通-美团外卖,\"1,234.50\",1234,1234.50",
+        )?;
+        let transaction = Cmb::new(r);
+        assert_eq!(transaction.date()?, "2024-03-15");
+        assert_eq!(transaction.payee()?, "财付通-美团外卖");
+        assert_eq!(transaction.fund()?, "1234");
+        assert_eq!(transaction.amount()?.to_string(), "1234.50");
+        assert_eq!(transaction.currency()?, "CNY");
+        assert_eq!(transaction.price()?, None);
+        assert_eq!(transaction.flow()?, Flow::Expense);
+        assert_eq!(
+            transaction.metadata()?,
+            vec![("posting_date", "2024-03-16")]
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn get_foreign_transaction() {
+        let r = gen_record(HEADERS, "20240315,20240316,AMAZON,86.40,5678,12.00(USD)")?;
+        let transaction = Cmb::new(r);
+        assert_eq!(
+            transaction.metadata()?,
+            vec![("posting_date", "2024-03-16")]
+        );
+        assert_eq!(transaction.amount()?.to_string(), "12.00");
+        assert_eq!(transaction.currency()?, "USD");
+        assert_eq!(
+            transaction.price()?.map(|(p, c)| (p.to_string(), c)),
+            Some(("86.40".to_owned(), "CNY"))
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn get_refund() {
+        let r = gen_record(
+            HEADERS,
+            "2024-03-15,2024-03-16,退款-AMAZON,-86.40,5678,USD 12.00",
+        )?;
+        let transaction = Cmb::new(r);
+        assert_eq!(transaction.flow()?, Flow::Income);
+        assert_eq!(transaction.amount()?.to_string(), "-12.00");
+        let r = gen_record(HEADERS, "2024-03-15,16/03,退款-AMAZON,-86.40,5678,")?;
+        assert!(Cmb::new(r).metadata().is_err());
+    }
+}
@@ -1,64 +1,377 @@
-use crate::lib::{Bean, Transaction};
+use crate::lib::{Bean, Reject, Transaction};
 use anyhow::anyhow;
-use csv::{ReaderBuilder, StringRecord, Trim};
-use fehler::throws;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use chrono::NaiveDate;
+use csv::{ErrorKind, ReaderBuilder, StringRecord, Trim};
+use encoding_rs::{Encoding, GB18030, UTF_8};
+use fehler::{throw, throws};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 type Error = anyhow::Error;
 
+/// How many leading lines are inspected when detecting the source of a file.
+const SNIFF_LINES: usize = 30;
+
+/// Column name to index mapping, built from the header row of the statement.
+pub type Headers = Rc<HashMap<String, usize>>;
+
+/// A statement row whose fields are addressed by header name.
+pub struct Record {
+    headers: Headers,
+    fields: StringRecord,
+}
+
+impl Record {
+    pub fn new(headers: Headers, fields: StringRecord) -> Self {
+        Self { headers, fields }
+    }
+
+    /// Field of an optional column.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|i| self.fields.get(*i))
+    }
+
+    /// Amount and currency of optional foreign currency columns, if the currency isn't CNY.
+    pub fn foreign_amount(&self, amount: &str, currency: &str) -> Option<(Decimal, &str)> {
+        let currency = self.get(currency).filter(|currency| {
+            currency.len() == 3
+                && currency.chars().all(|c| c.is_ascii_uppercase())
+                && *currency != "CNY"
+        })?;
+        let amount = self.get(amount).and_then(parse_amount)?;
+        Some((amount.abs(), currency))
+    }
+
+    #[throws]
+    pub fn pick<'a, F, T>(&'a self, name: &str, transform: F) -> T
+    where
+        F: Fn(&'a str) -> Option<T>,
+    {
+        let i = self
+            .headers
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown column {}", name))?;
+        self.fields
+            .get(*i)
+            .and_then(transform)
+            .ok_or_else(|| anyhow!("Can't get {} from {:?}", name, self.fields))?
+    }
+}
+
+impl std::fmt::Debug for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.fields)
+    }
+}
+
 #[throws]
-pub fn pick<'a, F, T>(record: &'a StringRecord, name: &str, i: usize, transform: F) -> T
-where
-    F: Fn(&'a str) -> Option<T>,
-{
+pub fn split_line(line: &str, delimiter: u8) -> StringRecord {
+    let mut record = StringRecord::new();
+    ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(Trim::All)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(line.as_bytes())
+        .read_record(&mut record)?;
     record
-        .get(i)
-        .and_then(transform)
-        .ok_or_else(|| anyhow!("Can't get {} from {:?} with index {}", name, record, i))?
 }
 
-pub struct Parser {
-    // start from 0
-    header_line: usize,
-    input: PathBuf,
-    edit: bool,
+/// Parse a date with one of the formats into `YYYY-MM-DD`, ignoring the time part if any.
+pub fn parse_date(s: &str, formats: &[&str]) -> Option<String> {
+    let date = s.split_whitespace().next()?;
+    formats
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(date, format).ok())
+        .map(|date| date.format("%Y-%m-%d").to_string())
 }
 
-impl Parser {
-    pub fn new(input: PathBuf, header_line: usize, edit: bool) -> Self {
+/// Parse an amount like `¥1,234.50`, ignoring currency symbols and thousands separators.
+pub fn parse_amount(s: &str) -> Option<Decimal> {
+    let amount = s
+        .chars()
+        .filter(|c| !matches!(c, ',' | '¥' | '￥' | ' '))
+        .collect::<String>();
+    amount.parse().ok()
+}
+
+/// Decode the statement to UTF-8. Without an explicit encoding, a BOM decides it, otherwise input
+/// that isn't valid UTF-8 is taken as GB18030 (a superset of GBK) like the Alipay exports.
+#[throws]
+pub fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> String {
+    let encoding = encoding.unwrap_or_else(|| match Encoding::for_bom(bytes) {
+        Some((encoding, _)) => encoding,
+        None if std::str::from_utf8(bytes).is_ok() => UTF_8,
+        None => GB18030,
+    });
+    let (contents, actual, has_errors) = encoding.decode(bytes);
+    if has_errors {
+        throw!(anyhow!("Input is not valid {}", actual.name()));
+    }
+    contents.into_owned()
+}
+
+/// Check whether the statement looks like one from a source, either by the banner in its preamble
+/// or by a header row containing all the source columns.
+pub fn sniff(contents: &str, banner: &str, columns: &[&str]) -> bool {
+    contents.lines().take(SNIFF_LINES).any(|line| {
+        line.contains(banner)
+            || split_line(line, b',')
+                .map(|record| {
+                    columns
+                        .iter()
+                        .all(|column| record.iter().any(|field| field == *column))
+                })
+                .unwrap_or(false)
+    })
+}
+
+fn missing_columns<'c>(record: &StringRecord, columns: &[&'c str]) -> Vec<&'c str> {
+    columns
+        .iter()
+        .filter(|column| !record.iter().any(|field| field == **column))
+        .copied()
+        .collect()
+}
+
+/// Find the header row, i.e. the line containing most of the expected columns.
+/// Fails with the missing column names if the best candidate is incomplete.
+#[throws]
+fn locate_header(contents: &str, columns: &[&str], delimiter: u8) -> usize {
+    let mut best: Option<(usize, Vec<&str>)> = None;
+    for (i, line) in contents.lines().enumerate() {
+        let missing = missing_columns(&split_line(line, delimiter)?, columns);
+        if missing.len() == columns.len() {
+            continue;
+        }
+        if best.as_ref().is_none_or(|(_, m)| missing.len() < m.len()) {
+            best = Some((i, missing));
+        }
+    }
+    match best {
+        Some((i, missing)) if missing.is_empty() => i,
+        Some((i, missing)) => throw!(anyhow!(
+            "Missing columns in header row (line {}): {}",
+            i + 1,
+            missing.join(", ")
+        )),
+        None => throw!(anyhow!(
+            "Can't find the header row, expected columns: {}",
+            columns.join(", ")
+        )),
+    }
+}
+
+/// A data row with its line number and raw text in the statement.
+pub struct Row {
+    pub line: usize,
+    pub raw: String,
+    pub record: Record,
+}
+
+/// Lines starting with it separate the data rows from the summary at the end of the statement.
+const FOOTER_SEPARATOR: &str = "---";
+
+pub struct Parser<'c> {
+    columns: &'c [&'c str],
+    delimiter: u8,
+    preamble: Option<usize>,
+}
+
+impl<'c> Parser<'c> {
+    pub fn new(columns: &'c [&'c str]) -> Self {
         Self {
-            input,
-            header_line,
-            edit,
+            columns,
+            delimiter: b',',
+            preamble: None,
         }
     }
 
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Take the line right after the preamble as the header row instead of looking for it.
+    pub fn preamble(mut self, lines: usize) -> Self {
+        self.preamble = Some(lines);
+        self
+    }
+
+    /// Parse the data rows, rows the CSV reader rejects are returned as errors.
     #[throws]
-    pub fn parse(&self) -> Vec<StringRecord> {
-        let mut file = File::open(&self.input)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        contents = contents
-            .lines()
-            .skip(self.header_line)
-            .collect::<Vec<_>>()
-            .join("\n");
+    pub fn parse(&self, contents: &str) -> Vec<Result<Row, Reject>> {
+        let header_line = match self.preamble {
+            Some(lines) => lines,
+            None => locate_header(contents, self.columns, self.delimiter)?,
+        };
+        let lines = contents.lines().skip(header_line).collect::<Vec<_>>();
+        let contents = lines.join("\n");
         let mut rdr = ReaderBuilder::new()
+            .delimiter(self.delimiter)
             .trim(Trim::All)
             .from_reader(contents.as_bytes());
-        rdr.records().filter_map(|result| result.ok()).collect()
+        let missing = missing_columns(rdr.headers()?, self.columns);
+        if !missing.is_empty() {
+            throw!(anyhow!(
+                "Missing columns in header row (line {}): {}",
+                header_line + 1,
+                missing.join(", ")
+            ));
+        }
+        let mut headers = HashMap::new();
+        for (i, name) in rdr.headers()?.iter().enumerate() {
+            if !name.is_empty() {
+                headers.entry(name.to_owned()).or_insert(i);
+            }
+        }
+        let headers = Rc::new(headers);
+        let mut rows = vec![];
+        for result in rdr.records() {
+            let position = match &result {
+                Ok(fields) => fields.position(),
+                Err(e) => e.position(),
+            };
+            // Line of the position starts from 1 at the header row
+            let line = position.map_or(0, |p| p.line() as usize);
+            let raw = line
+                .checked_sub(1)
+                .and_then(|i| lines.get(i))
+                .map_or("", |raw| raw.trim());
+            if raw.starts_with(FOOTER_SEPARATOR) {
+                break;
+            }
+            let line = line + header_line;
+            rows.push(match result {
+                Ok(fields) => Ok(Row {
+                    line,
+                    raw: raw.to_owned(),
+                    record: Record::new(headers.clone(), fields),
+                }),
+                Err(e) => Err(Reject {
+                    line,
+                    raw: raw.to_owned(),
+                    reason: match e.kind() {
+                        ErrorKind::UnequalLengths {
+                            expected_len, len, ..
+                        } => format!("Found {} fields, expected {}", len, expected_len),
+                        _ => e.to_string(),
+                    },
+                }),
+            });
+        }
+        rows
     }
 
     #[throws]
-    pub fn output<F, T: 'static + Transaction>(&self, mut bean: Bean, constructor: F) -> String
+    pub fn parse_into<'a, F, T: 'static + Transaction>(
+        &self,
+        contents: &str,
+        mut bean: Bean<'a>,
+        constructor: F,
+    ) -> Bean<'a>
     where
-        F: Fn(StringRecord) -> T,
+        F: Fn(Record) -> T,
     {
-        for record in self.parse()? {
-            bean.add(constructor(record));
+        for row in self.parse(contents)? {
+            match row {
+                Ok(row) => {
+                    bean.add_row(row.line, &row.raw, constructor(row.record));
+                }
+                Err(reject) => bean.reject(reject),
+            }
         }
-        bean.output(self.edit)?
+        bean
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, parse_amount, parse_date, sniff, Parser};
+    use encoding_rs::{GBK, UTF_16LE};
+    use fehler::throws;
+
+    type Error = anyhow::Error;
+
+    static COLUMNS: &[&str] = &["时间", "金额"];
+
+    #[test]
+    #[throws]
+    fn it_locate_header_after_preamble() {
+        let parser = Parser::new(COLUMNS);
+        let rows = parser
+            .parse("账单明细\n起始时间：[2020-03-01]\n备注,金额 ,时间\nx, 1.00,2020-03-02\n")?;
+        assert_eq!(rows.len(), 1);
+        let row = rows[0].as_ref().unwrap();
+        assert_eq!(row.line, 4);
+        assert_eq!(row.record.pick("时间", Some)?, "2020-03-02");
+        assert_eq!(row.record.pick("金额", Some)?, "1.00");
+    }
+
+    #[test]
+    #[throws]
+    fn it_reject_malformed_rows() {
+        let parser = Parser::new(COLUMNS);
+        let rows = parser
+            .parse("时间,金额\n2020-03-02,1.00\n2020-03-03\n2020-03-04,2.00\n-----\n共3笔记录\n")?;
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].is_ok());
+        let reject = rows[1].as_ref().err().unwrap();
+        assert_eq!(reject.line, 3);
+        assert_eq!(reject.raw, "2020-03-03");
+        assert_eq!(reject.reason, "Found 1 fields, expected 2");
+        assert_eq!(rows[2].as_ref().unwrap().line, 4);
+    }
+
+    #[test]
+    fn it_report_missing_columns() {
+        let parser = Parser::new(COLUMNS);
+        let err = parser.parse("preamble\n时间,备注\n").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Missing columns in header row (line 2): 金额"
+        );
+    }
+
+    #[test]
+    fn it_sniff_banner_or_header() {
+        assert!(sniff("账单明细\n时间,金额\n", "账单明细", &["交易号"]));
+        assert!(sniff("preamble\n 时间 ,金额,备注\n", "账单明细", COLUMNS));
+        assert!(!sniff("preamble\n时间,备注\n", "账单明细", COLUMNS));
+    }
+
+    #[test]
+    #[throws]
+    fn it_decode_non_utf8() {
+        let (gbk, _, _) = GBK.encode("支付宝交易记录明细查询");
+        assert_eq!(decode(&gbk, None)?, "支付宝交易记录明细查询");
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend(
+            "交易时间"
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes().to_vec()),
+        );
+        assert_eq!(decode(&utf16, None)?, "交易时间");
+        assert_eq!(decode(&utf16[2..], Some(UTF_16LE))?, "交易时间");
+        assert_eq!(decode("微信".as_bytes(), None)?, "微信");
+    }
+
+    #[test]
+    fn it_parse_date_and_amount() {
+        let formats = &["%Y-%m-%d", "%Y/%m/%d"];
+        assert_eq!(
+            parse_date("2024/3/5 12:00", formats),
+            Some("2024-03-05".to_owned())
+        );
+        assert_eq!(parse_date("03/05", formats), None);
+        assert_eq!(
+            parse_amount("¥1,234.50").map(|d| d.to_string()),
+            Some("1234.50".to_owned())
+        );
+        assert_eq!(
+            parse_amount("-12.00").map(|d| d.to_string()),
+            Some("-12.00".to_owned())
+        );
     }
 }
@@ -0,0 +1,494 @@
+use super::csv::{parse_amount, parse_date, Parser, Record};
+use crate::lib::{Bean, Flow, Transaction};
+use anyhow::{anyhow, Context};
+use encoding_rs::Encoding;
+use fehler::{throw, throws};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use toml_edit::{Document, Item, TableLike};
+
+type Error = anyhow::Error;
+
+static DEFAULT_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y/%m/%d", "%Y%m%d"];
+
+/// How the sign of a single amount column tells the flow.
+#[derive(Debug, PartialEq)]
+enum Sign {
+    ExpenseNegative,
+    ExpensePositive,
+}
+
+/// Column names of the transaction fields, optional ones are `None` if not in the statement.
+#[derive(Debug, Default)]
+struct Columns {
+    date: String,
+    payee: String,
+    narration: Option<String>,
+    amount: Option<String>,
+    debit: Option<String>,
+    credit: Option<String>,
+    flow: Option<String>,
+    fund: Option<String>,
+    id: Option<String>,
+    currency: Option<String>,
+}
+
+impl Columns {
+    fn names(&self) -> Vec<&str> {
+        let optional = [
+            &self.narration,
+            &self.amount,
+            &self.debit,
+            &self.credit,
+            &self.flow,
+            &self.fund,
+            &self.id,
+            &self.currency,
+        ];
+        let mut names = vec![self.date.as_str(), self.payee.as_str()];
+        names.extend(optional.iter().filter_map(|name| name.as_deref()));
+        names
+    }
+}
+
+/// Layout of a CSV statement, loaded from a TOML profile so a new bank export can be imported
+/// without writing an importer.
+#[derive(Debug)]
+pub struct Profile {
+    name: String,
+    fund_account: String,
+    pub encoding: Option<&'static Encoding>,
+    delimiter: u8,
+    preamble: Option<usize>,
+    date_formats: Vec<String>,
+    currency: String,
+    sign: Sign,
+    columns: Columns,
+    income: Vec<String>,
+    expense: Vec<String>,
+    skip: Vec<(String, Regex)>,
+}
+
+#[throws]
+fn as_str<'a>(key: &str, item: &'a Item) -> &'a str {
+    item.as_str()
+        .ok_or_else(|| anyhow!("{} should be a string", key))?
+}
+
+/// A string or an array of strings.
+#[throws]
+fn as_strings(key: &str, item: &Item) -> Vec<String> {
+    match (item.as_str(), item.as_array()) {
+        (Some(s), _) => vec![s.to_owned()],
+        (_, Some(array)) => array
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(|s| s.to_owned())
+                    .ok_or_else(|| anyhow!("{} should be strings", key))
+            })
+            .collect::<Result<_, _>>()?,
+        _ => throw!(anyhow!("{} should be a string or an array of strings", key)),
+    }
+}
+
+#[throws]
+fn as_table<'a>(key: &str, item: &'a Item) -> &'a dyn TableLike {
+    item.as_table_like()
+        .ok_or_else(|| anyhow!("{} should be a table", key))?
+}
+
+impl Profile {
+    #[throws]
+    pub fn from_file(path: &Path) -> Self {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read CSV profile {}", path.display()))?;
+        Self::from_str(&content)
+            .with_context(|| format!("Invalid CSV profile {}", path.display()))?
+    }
+
+    #[throws]
+    pub fn from_str(content: &str) -> Self {
+        let doc = content.parse::<Document>()?;
+        let mut profile = Profile {
+            name: "generic".to_owned(),
+            fund_account: String::new(),
+            encoding: None,
+            delimiter: b',',
+            preamble: None,
+            date_formats: DEFAULT_DATE_FORMATS.iter().map(|s| s.to_string()).collect(),
+            currency: "CNY".to_owned(),
+            sign: Sign::ExpenseNegative,
+            columns: Columns::default(),
+            income: vec![],
+            expense: vec![],
+            skip: vec![],
+        };
+        for (key, item) in doc.as_table().iter() {
+            match key {
+                "name" => profile.name = as_str(key, item)?.to_owned(),
+                "fund_account" => profile.fund_account = as_str(key, item)?.to_owned(),
+                "encoding" => {
+                    let label = as_str(key, item)?;
+                    profile.encoding = Some(
+                        Encoding::for_label(label.as_bytes())
+                            .ok_or_else(|| anyhow!("Unknown encoding: {}", label))?,
+                    );
+                }
+                "delimiter" => {
+                    profile.delimiter = match as_str(key, item)?.as_bytes() {
+                        [delimiter] => *delimiter,
+                        _ => throw!(anyhow!("delimiter should be a single ASCII character")),
+                    }
+                }
+                "preamble" => {
+                    let lines = item
+                        .as_integer()
+                        .filter(|lines| *lines >= 0)
+                        .ok_or_else(|| anyhow!("preamble should be a number of lines"))?;
+                    profile.preamble = Some(lines as usize);
+                }
+                "date_format" => profile.date_formats = as_strings(key, item)?,
+                "currency" => profile.currency = as_str(key, item)?.to_owned(),
+                "sign" => {
+                    profile.sign = match as_str(key, item)? {
+                        "expense_negative" => Sign::ExpenseNegative,
+                        "expense_positive" => Sign::ExpensePositive,
+                        sign => throw!(anyhow!(
+                            "Invalid sign {}, should be expense_negative or expense_positive",
+                            sign
+                        )),
+                    }
+                }
+                "columns" => {
+                    for (key, item) in as_table(key, item)?.iter() {
+                        let name = as_str(key, item)?.to_owned();
+                        let columns = &mut profile.columns;
+                        match key {
+                            "date" => columns.date = name,
+                            "payee" => columns.payee = name,
+                            "narration" => columns.narration = Some(name),
+                            "amount" => columns.amount = Some(name),
+                            "debit" => columns.debit = Some(name),
+                            "credit" => columns.credit = Some(name),
+                            "flow" => columns.flow = Some(name),
+                            "fund" => columns.fund = Some(name),
+                            "id" => columns.id = Some(name),
+                            "currency" => columns.currency = Some(name),
+                            _ => throw!(anyhow!("Unknown column key {}", key)),
+                        }
+                    }
+                }
+                "flow" => {
+                    for (key, item) in as_table(key, item)?.iter() {
+                        match key {
+                            "income" => profile.income = as_strings(key, item)?,
+                            "expense" => profile.expense = as_strings(key, item)?,
+                            _ => throw!(anyhow!(
+                                "Unknown flow key {}, should be income or expense",
+                                key
+                            )),
+                        }
+                    }
+                }
+                "skip" => {
+                    for (column, item) in as_table(key, item)?.iter() {
+                        let re = Regex::new(as_str(column, item)?)?;
+                        profile.skip.push((column.to_owned(), re));
+                    }
+                }
+                _ => throw!(anyhow!("Unknown key {}", key)),
+            }
+        }
+        let columns = &profile.columns;
+        if profile.fund_account.is_empty() {
+            throw!(anyhow!("fund_account is required"));
+        }
+        if columns.date.is_empty() || columns.payee.is_empty() {
+            throw!(anyhow!("columns.date and columns.payee are required"));
+        }
+        match (&columns.amount, &columns.debit, &columns.credit) {
+            (Some(_), None, None) | (None, Some(_), Some(_)) => {}
+            _ => throw!(anyhow!(
+                "Specify either columns.amount, or both columns.debit and columns.credit"
+            )),
+        }
+        profile
+    }
+}
+
+/// A row of a statement described by a CSV profile.
+pub struct Generic {
+    record: Record,
+    profile: Rc<Profile>,
+    date: Option<String>,
+}
+
+impl<'a> Generic {
+    pub fn new(record: Record, profile: Rc<Profile>) -> Self {
+        let date = record.get(&profile.columns.date).and_then(|s| {
+            let formats = profile
+                .date_formats
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>();
+            parse_date(s, &formats)
+        });
+        Self {
+            record,
+            profile,
+            date,
+        }
+    }
+
+    #[throws]
+    fn pick<F, T>(&'a self, name: &str, transform: F) -> T
+    where
+        F: Fn(&'a str) -> Option<T>,
+    {
+        self.record.pick(name, transform)?
+    }
+
+    fn default_transform(s: &str) -> Option<&str> {
+        Some(s)
+    }
+
+    #[throws]
+    fn optional(&self, column: &Option<String>) -> &str {
+        match column {
+            Some(name) => self.pick(name, Self::default_transform)?,
+            None => "",
+        }
+    }
+
+    /// Amount of a debit or credit column, empty cells are zero.
+    #[throws]
+    fn pick_side(&self, column: &Option<String>) -> Decimal {
+        match self.optional(column)? {
+            "" => Decimal::ZERO,
+            s => parse_amount(s).ok_or_else(|| anyhow!("Invalid amount: {}", s))?,
+        }
+    }
+}
+
+impl Transaction for Generic {
+    #[throws]
+    fn date(&self) -> &str {
+        match &self.date {
+            Some(date) => date,
+            None => self.pick(&self.profile.columns.date, Self::default_transform)?,
+        }
+    }
+
+    #[throws]
+    fn payee(&self) -> &str {
+        self.pick(&self.profile.columns.payee, Self::default_transform)?
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.optional(&self.profile.columns.fund)?
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        self.optional(&self.profile.columns.narration)?
+    }
+
+    #[throws]
+    fn id(&self) -> &str {
+        self.optional(&self.profile.columns.id)?
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        let columns = &self.profile.columns;
+        match &columns.amount {
+            Some(name) => {
+                let amount: Decimal = self.pick(name, parse_amount)?;
+                match (&columns.flow, self.flow()?) {
+                    (Some(_), Flow::Income) => -amount.abs(),
+                    (Some(_), _) => amount.abs(),
+                    (None, _) if self.profile.sign == Sign::ExpenseNegative => -amount,
+                    (None, _) => amount,
+                }
+            }
+            None => self.pick_side(&columns.debit)? - self.pick_side(&columns.credit)?,
+        }
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        match self.optional(&self.profile.columns.currency)? {
+            "" => &self.profile.currency,
+            currency => currency,
+        }
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        let profile = &self.profile;
+        let columns = &profile.columns;
+        if let Some(name) = &columns.flow {
+            let flow = self.pick(name, Self::default_transform)?;
+            if profile.income.iter().any(|s| s == flow) {
+                Flow::Income
+            } else if profile.expense.iter().any(|s| s == flow) {
+                Flow::Expense
+            } else {
+                Flow::from(flow)
+            }
+        } else if let Some(name) = &columns.amount {
+            let amount: Decimal = self.pick(name, parse_amount)?;
+            let expense = match profile.sign {
+                Sign::ExpenseNegative => amount.is_sign_negative(),
+                Sign::ExpensePositive => amount.is_sign_positive(),
+            };
+            if expense {
+                Flow::Expense
+            } else {
+                Flow::Income
+            }
+        } else if self.pick_side(&columns.debit)?.is_zero() {
+            Flow::Income
+        } else {
+            Flow::Expense
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.profile.skip.iter().any(|(column, re)| {
+            self.record
+                .get(column)
+                .is_some_and(|field| re.is_match(field))
+        })
+    }
+
+    fn source(&self) -> &str {
+        &self.profile.name
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.record)
+    }
+}
+
+#[throws]
+pub fn import<'p>(contents: &str, profile: &'p Rc<Profile>) -> Bean<'p> {
+    let columns = profile.columns.names();
+    let mut parser = Parser::new(&columns).delimiter(profile.delimiter);
+    if let Some(lines) = profile.preamble {
+        parser = parser.preamble(lines);
+    }
+    let bean = Bean::new(&profile.fund_account);
+    parser.parse_into(contents, bean, |record| {
+        Generic::new(record, profile.clone())
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, Generic, Profile};
+    use crate::lib::{Flow, Transaction};
+    use crate::test_helpers::gen_record;
+    use fehler::throws;
+    use std::rc::Rc;
+
+    type Error = anyhow::Error;
+
+    static DEBIT_CREDIT: &str = r#"
+name = "icbc"
+fund_account = "Assets:ICBC"
+date_format = "%Y%m%d"
+
+[columns]
+date = "交易日期"
+payee = "对方户名"
+narration = "摘要"
+debit = "支出"
+credit = "收入"
+
+[skip]
+"摘要" = "^利息$"
+"#;
+
+    #[test]
+    #[throws]
+    fn get_debit_and_credit() {
+        let profile = Rc::new(Profile::from_str(DEBIT_CREDIT)?);
+        let headers = &["交易日期", "摘要", "对方户名", "支出", "收入"];
+        let t = Generic::new(
+            gen_record(headers, "20240301,消费,超市,\"1,200.00\",")?,
+            profile.clone(),
+        );
+        assert_eq!(t.date()?, "2024-03-01");
+        assert_eq!(t.payee()?, "超市");
+        assert_eq!(t.narration()?, "消费");
+        assert_eq!(t.amount()?.to_string(), "1200.00");
+        assert_eq!(t.flow()?, Flow::Expense);
+        assert_eq!(t.source(), "icbc");
+        let t = Generic::new(
+            gen_record(headers, "20240302,工资,公司,,500")?,
+            profile.clone(),
+        );
+        assert_eq!(t.amount()?.to_string(), "-500");
+        assert_eq!(t.flow()?, Flow::Income);
+        let t = Generic::new(gen_record(headers, "20240303,利息,银行,,0.1")?, profile);
+        assert!(!t.is_valid());
+    }
+
+    #[test]
+    #[throws]
+    fn get_signed_amount_with_flow() {
+        let profile = Rc::new(Profile::from_str(
+            r#"
+fund_account = "Assets:Bank"
+sign = "expense_positive"
+columns = { date = "日期", payee = "商户", amount = "金额", flow = "方向" }
+flow = { income = "贷", expense = "借" }
+"#,
+        )?);
+        let headers = &["日期", "商户", "金额", "方向"];
+        let t = Generic::new(
+            gen_record(headers, "2024-03-01,商户,12.5,贷")?,
+            profile.clone(),
+        );
+        assert_eq!(t.flow()?, Flow::Income);
+        assert_eq!(t.amount()?.to_string(), "-12.5");
+        assert_eq!(t.source(), "generic");
+        let t = Generic::new(gen_record(headers, "2024-03-01,商户,12.5,借")?, profile);
+        assert_eq!(t.flow()?, Flow::Expense);
+        assert_eq!(t.amount()?.to_string(), "12.5");
+    }
+
+    #[test]
+    fn it_reject_invalid_profile() {
+        assert!(Profile::from_str("fund_account = \"Assets:Bank\"").is_err());
+        assert!(Profile::from_str(
+            "fund_account = \"Assets:Bank\"\ncolumns = { date = \"d\", payee = \"p\" }"
+        )
+        .is_err());
+        assert!(Profile::from_str("unknown = 1").is_err());
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_with_preamble_and_delimiter() {
+        let profile = Rc::new(Profile::from_str(
+            r#"
+fund_account = "Assets:Bank"
+delimiter = ";"
+preamble = 2
+columns = { date = "date", payee = "payee", amount = "amount" }
+"#,
+        )?);
+        let contents = "Bank statement\naccount;123\ndate;payee;amount\n2024-03-01;shop;-8\n";
+        let bean = import(contents, &profile)?;
+        assert!(bean.rejects().is_empty());
+        let contents = "Bank statement\n\ndate;payee\n2024-03-01;shop\n";
+        assert!(import(contents, &profile).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+/// An element of a SGML or XML document, `text` is empty for aggregates and closing tags.
+#[derive(Debug, PartialEq)]
+pub struct Element<'a> {
+    pub tag: &'a str,
+    attrs: &'a str,
+    pub text: String,
+    pub line: usize,
+    pub closing: bool,
+}
+
+impl Element<'_> {
+    /// Value of an attribute of the opening tag, values must be quoted.
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        let mut rest = self.attrs;
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &value[quote.len_utf8()..];
+            let end = value.find(quote)?;
+            if key == name {
+                return Some(&value[..end]);
+            }
+            rest = &value[end + 1..];
+        }
+        None
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+static COMMENT: (&str, &str) = ("<!--", "-->");
+static CDATA: (&str, &str) = ("<![CDATA[", "]]>");
+
+/// Split a comment or CDATA section at the start of the string into its contents and the rest.
+fn section<'a>(s: &'a str, (open, close): (&str, &str)) -> Option<(&'a str, &'a str)> {
+    let s = s.strip_prefix(open)?;
+    match s.find(close) {
+        Some(end) => Some((&s[..end], &s[end + close.len()..])),
+        None => Some((s, "")),
+    }
+}
+
+/// Text up to the next tag, CDATA sections are taken as is and comments are skipped.
+fn text(s: &str) -> String {
+    let mut text = String::new();
+    let mut rest = s;
+    loop {
+        let end = rest.find('<').unwrap_or(rest.len());
+        text.push_str(&unescape(&rest[..end]));
+        rest = &rest[end..];
+        if let Some((cdata, next)) = section(rest, CDATA) {
+            text.push_str(cdata);
+            rest = next;
+        } else if let Some((_, next)) = section(rest, COMMENT) {
+            rest = next;
+        } else {
+            break;
+        }
+    }
+    text.trim().to_owned()
+}
+
+/// Scan the elements of both SGML (e.g. OFX 1.x, leaf elements aren't closed) and XML documents.
+/// Headers, comments, CDATA sections and processing instructions are skipped, namespace prefixes
+/// are stripped and self-closing elements are followed by their closing tags.
+pub fn scan(contents: &str) -> Vec<Element<'_>> {
+    let mut elements = vec![];
+    let mut rest = contents;
+    let mut line = 1;
+    while let Some(start) = rest.find('<') {
+        line += rest[..start].matches('\n').count();
+        rest = &rest[start..];
+        // Comments and CDATA may contain `>`
+        if let Some((skipped, next)) = section(rest, COMMENT).or_else(|| section(rest, CDATA)) {
+            line += skipped.matches('\n').count();
+            rest = next;
+            continue;
+        }
+        rest = &rest[1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = rest[..end].trim();
+        line += tag.matches('\n').count();
+        rest = &rest[end + 1..];
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        let (tag, closing) = match tag.strip_prefix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let (tag, self_closing) = match tag.strip_suffix('/') {
+            Some(tag) => (tag.trim_end(), true),
+            None => (tag, false),
+        };
+        let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let name = name.rsplit(':').next().unwrap_or(name);
+        let text = if closing || self_closing {
+            String::new()
+        } else {
+            text(rest)
+        };
+        elements.push(Element {
+            tag: name,
+            attrs,
+            text,
+            line,
+            closing,
+        });
+        if self_closing {
+            elements.push(Element {
+                tag: name,
+                attrs: "",
+                text: String::new(),
+                line,
+                closing: true,
+            });
+        }
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scan;
+
+    #[test]
+    fn it_scan_elements() {
+        let elements = scan(
+            "<?xml version=\"1.0\"?>\n<ns:Doc xmlns:ns=\"urn\">\n<Amt Ccy='EUR'>1 &amp; 2</Amt><Empty/>",
+        );
+        let summary = elements
+            .iter()
+            .map(|e| (e.tag, e.text.as_str(), e.line, e.closing))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("Doc", "", 2, false),
+                ("Amt", "1 & 2", 3, false),
+                ("Amt", "", 3, true),
+                ("Empty", "", 3, false),
+                ("Empty", "", 3, true),
+            ]
+        );
+        assert_eq!(elements[0].attr("xmlns:ns"), Some("urn"));
+        assert_eq!(elements[1].attr("Ccy"), Some("EUR"));
+        assert_eq!(elements[1].attr("Cd"), None);
+    }
+
+    #[test]
+    fn it_scan_sgml() {
+        let elements = scan("OFXHEADER:100\n\n<OFX>\n<CODE>0\n</OFX>");
+        let summary = elements
+            .iter()
+            .map(|e| (e.tag, e.text.as_str(), e.closing))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![("OFX", "", false), ("CODE", "0", false), ("OFX", "", true)]
+        );
+    }
+
+    #[test]
+    fn it_scan_comments_and_cdata() {
+        let elements = scan(
+            "<Doc>\n<!-- <Skip> a > b\n -->\n<Nm><![CDATA[A > B & <C>]]></Nm>\n<Cd>1<!-- > --></Cd></Doc>",
+        );
+        let summary = elements
+            .iter()
+            .map(|e| (e.tag, e.text.as_str(), e.line, e.closing))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("Doc", "", 1, false),
+                ("Nm", "A > B & <C>", 4, false),
+                ("Nm", "", 4, true),
+                ("Cd", "1", 5, false),
+                ("Cd", "", 5, true),
+                ("Doc", "", 5, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reject_unquoted_attributes() {
+        let elements = scan("<Amt Ccy=€>1</Amt><Amt Ccy=\"€\">2</Amt>");
+        assert_eq!(elements[0].attr("Ccy"), None);
+        assert_eq!(elements[2].attr("Ccy"), Some("€"));
+    }
+}
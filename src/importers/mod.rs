@@ -1,3 +1,47 @@
 pub mod alipay;
+pub mod alipay2;
+pub mod archive;
+pub mod camt053;
+pub mod cmb;
 pub mod csv;
+pub mod generic;
+pub mod markup;
+pub mod mt940;
+pub mod ofx;
 pub mod wechat;
+pub mod xlsx;
+
+use encoding_rs::Encoding;
+use fehler::throws;
+use std::fs;
+use std::path::Path;
+
+type Error = anyhow::Error;
+
+#[throws]
+fn load(
+    name: &Path,
+    bytes: &[u8],
+    encoding: Option<&'static Encoding>,
+    password: Option<&str>,
+) -> String {
+    match name.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("xlsx") => xlsx::to_csv(bytes)?,
+        Some(ext) if ext.eq_ignore_ascii_case("zip") => {
+            let (name, bytes) = archive::extract(bytes, password)?;
+            load(Path::new(&name), &bytes, encoding, None)?
+        }
+        Some(ext) if ext.eq_ignore_ascii_case("ofx") || ext.eq_ignore_ascii_case("qfx") => {
+            ofx::decode(bytes, encoding)?
+        }
+        _ => csv::decode(bytes, encoding)?,
+    }
+}
+
+/// Load the statement as CSV text, xlsx workbooks are converted by their first worksheet and zip
+/// archives are extracted in memory.
+#[throws]
+pub fn read(input: &Path, encoding: Option<&'static Encoding>, password: Option<&str>) -> String {
+    let bytes = fs::read(input)?;
+    load(input, &bytes, encoding, password)?
+}
@@ -0,0 +1,433 @@
+use crate::lib::{cached_regex, Balance, Bean, Flow, Reject, Transaction};
+use anyhow::anyhow;
+use chrono::{Datelike, NaiveDate};
+use fehler::{throw, throws};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::sync::OnceLock;
+
+type Error = anyhow::Error;
+
+/// `:61:` statement line: value date, optional entry date, debit/credit mark, optional funds code,
+/// amount, transaction type and references.
+static STATEMENT_LINE: &str =
+    r"^(\d{6})(\d{4})?(RC|RD|C|D)[A-Z]?(\d+,\d*)([A-Z][A-Z0-9]{3})([^/\n]*)(?://([^\n]*))?";
+/// `:60F:` or `:62F:` balance: debit/credit mark, date, currency and amount.
+static BALANCE: &str = r"^(C|D)(\d{6})([A-Z]{3})(\d+,\d*)";
+
+fn statement_line() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    cached_regex(&CELL, STATEMENT_LINE)
+}
+
+fn balance() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    cached_regex(&CELL, BALANCE)
+}
+
+#[throws]
+fn parse_date(yymmdd: &str) -> NaiveDate {
+    NaiveDate::parse_from_str(&format!("20{}", yymmdd), "%Y%m%d")
+        .map_err(|_| anyhow!("Invalid date: {}", yymmdd))?
+}
+
+#[throws]
+fn parse_amount(s: &str) -> Decimal {
+    // The decimal comma is mandatory even without decimals, like `500,`
+    s.replace(',', ".")
+        .trim_end_matches('.')
+        .parse::<Decimal>()
+        .map_err(|_| anyhow!("Invalid amount: {}", s))?
+}
+
+/// The entry date only has month and day, which may fall into the year around the value date.
+#[throws]
+fn entry_date(value_date: NaiveDate, mmdd: &str) -> NaiveDate {
+    let month: u32 = mmdd[..2].parse()?;
+    let year = match (value_date.month(), month) {
+        (1, 12) => value_date.year() - 1,
+        (12, 1) => value_date.year() + 1,
+        _ => value_date.year(),
+    };
+    NaiveDate::parse_from_str(&format!("{}{}", year, mmdd), "%Y%m%d")
+        .map_err(|_| anyhow!("Invalid entry date: {}", mmdd))?
+}
+
+/// Counterparty name and remittance information of the `:86:` field. The structured format of
+/// German banks separates subfields by `?`, otherwise the SWIFT codes `/NAME/` and `/REMI/` are
+/// looked for, or the whole text is taken as remittance information.
+fn parse_information(text: &str) -> (String, String) {
+    if text.len() > 3 && text.is_char_boundary(3) && text[3..].starts_with('?') {
+        let mut name = String::new();
+        let mut remittance = vec![];
+        for subfield in text[3..].split('?').skip(1) {
+            let split = subfield
+                .char_indices()
+                .nth(2)
+                .map_or(subfield.len(), |(i, _)| i);
+            let (code, value) = subfield.split_at(split);
+            match code {
+                "20" | "21" | "22" | "23" | "24" | "25" | "26" | "27" | "28" | "29" | "60"
+                | "61" | "62" | "63" => remittance.push(value.trim()),
+                "32" | "33" => name.push_str(value.trim()),
+                _ => {}
+            }
+        }
+        (name, remittance.join(" "))
+    } else if text.starts_with('/') {
+        let mut name = String::new();
+        let mut remittance = String::new();
+        let mut parts = text.split('/').skip(1);
+        while let (Some(code), Some(value)) = (parts.next(), parts.next()) {
+            match code {
+                "NAME" => name = value.trim().to_owned(),
+                "REMI" => remittance = value.trim().to_owned(),
+                _ => {}
+            }
+        }
+        (name, remittance)
+    } else {
+        (String::new(), text.trim().to_owned())
+    }
+}
+
+/// A `:61:` statement line with the following `:86:` information of a SWIFT MT940 statement.
+#[derive(Debug)]
+pub struct Mt940 {
+    date: String,
+    value_date: String,
+    is_debit: bool,
+    amount: Decimal,
+    currency: String,
+    id: String,
+    payee: String,
+    narration: String,
+    account: String,
+}
+
+impl Mt940 {
+    #[throws]
+    fn parse(line: &str, account: &str, currency: &str) -> Self {
+        let captures = statement_line()
+            .captures(line)
+            .ok_or_else(|| anyhow!("Invalid statement line: {}", line))?;
+        let value_date = parse_date(&captures[1])?;
+        let date = match captures.get(2) {
+            Some(mmdd) => entry_date(value_date, mmdd.as_str())?,
+            None => value_date,
+        };
+        // Reversals of credits are debits and vice versa
+        let is_debit = matches!(&captures[3], "D" | "RC");
+        let reference = captures[6].trim();
+        let bank_reference = captures.get(7).map_or("", |m| m.as_str().trim());
+        let id = if !bank_reference.is_empty() {
+            bank_reference
+        } else if reference != "NONREF" {
+            reference
+        } else {
+            ""
+        };
+        Self {
+            date: date.format("%Y-%m-%d").to_string(),
+            value_date: value_date.format("%Y-%m-%d").to_string(),
+            is_debit,
+            amount: parse_amount(&captures[4])?,
+            currency: currency.to_owned(),
+            id: id.to_owned(),
+            payee: String::new(),
+            narration: String::new(),
+            account: account.to_owned(),
+        }
+    }
+}
+
+impl Transaction for Mt940 {
+    #[throws]
+    fn date(&self) -> &str {
+        self.date.as_str()
+    }
+
+    #[throws]
+    fn payee(&self) -> &str {
+        self.payee.as_str()
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.account.as_str()
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        self.narration.as_str()
+    }
+
+    #[throws]
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    #[throws]
+    fn metadata(&self) -> Vec<(&str, &str)> {
+        vec![("value_date", self.value_date.as_str())]
+    }
+
+    #[throws]
+    fn amount(&self) -> Decimal {
+        if self.is_debit {
+            self.amount
+        } else {
+            -self.amount
+        }
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        self.currency.as_str()
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        if self.is_debit {
+            Flow::Expense
+        } else {
+            Flow::Income
+        }
+    }
+
+    fn source(&self) -> &str {
+        "mt940"
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[throws]
+fn parse_balance(value: &str) -> (NaiveDate, Decimal, String) {
+    let captures = balance()
+        .captures(value)
+        .ok_or_else(|| anyhow!("Invalid balance: {}", value))?;
+    let mut amount = parse_amount(&captures[4])?;
+    if &captures[1] == "D" {
+        amount = -amount;
+    }
+    (parse_date(&captures[2])?, amount, captures[3].to_owned())
+}
+
+/// Fields of the statement as `(line, tag, value)`, continuation lines are joined to their fields.
+fn fields(contents: &str) -> Vec<(usize, &str, String)> {
+    let mut fields: Vec<(usize, &str, String)> = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        let field = line
+            .strip_prefix(':')
+            .and_then(|rest| rest.split_once(':'))
+            .filter(|(tag, _)| tag.len() <= 3 && tag.chars().all(|c| c.is_ascii_alphanumeric()));
+        match (field, fields.last_mut()) {
+            (Some((tag, value)), _) => fields.push((i + 1, tag, value.to_owned())),
+            // End of the message block
+            (None, _) if line.starts_with('-') => {}
+            (None, Some((_, tag, value))) if *tag == "86" => {
+                // Structured information is wrapped at a fixed width
+                if !value.contains('?') {
+                    value.push(' ');
+                }
+                value.push_str(line);
+            }
+            (None, Some((_, _, value))) => {
+                value.push('\n');
+                value.push_str(line);
+            }
+            (None, None) => {}
+        }
+    }
+    fields
+}
+
+pub fn is_match(contents: &str) -> bool {
+    let has = |tag: &str| contents.lines().any(|line| line.starts_with(tag));
+    has(":20:") && has(":60F:") && has(":61:")
+}
+
+/// State of the statement being read, a statement line is pending until its information field.
+struct Reader {
+    bean: Bean<'static>,
+    account: String,
+    currency: String,
+    pending: Option<(usize, String, Mt940)>,
+    /// Opening balance and the movement of the statement lines since then.
+    opening: Option<Decimal>,
+    movement: Decimal,
+}
+
+impl Reader {
+    fn flush(&mut self) {
+        if let Some((line, raw, transaction)) = self.pending.take() {
+            if let Some(t) = self.bean.add_row(line, &raw, transaction) {
+                self.movement -= t.amount().unwrap_or_default();
+            }
+        }
+    }
+
+    #[throws]
+    fn read(&mut self, line: usize, tag: &str, value: &str) {
+        if tag != "86" {
+            self.flush();
+        }
+        match tag {
+            "20" => self.currency.clear(),
+            "25" => self.account = value.trim().to_owned(),
+            "60F" | "60M" => {
+                let (_, amount, currency) = parse_balance(value)?;
+                self.currency = currency;
+                self.opening = Some(amount);
+                self.movement = Decimal::ZERO;
+            }
+            "61" => {
+                if self.currency.is_empty() {
+                    throw!(anyhow!("Missing opening balance before the statement line"));
+                }
+                let transaction = Mt940::parse(value, &self.account, &self.currency)?;
+                self.pending = Some((line, format!(":61:{}", value), transaction));
+            }
+            "86" => {
+                if let Some((_, raw, transaction)) = &mut self.pending {
+                    let (payee, narration) = parse_information(value);
+                    transaction.payee = payee;
+                    transaction.narration = narration;
+                    raw.push_str(&format!(" :86:{}", value));
+                }
+            }
+            "62F" | "62M" => {
+                let (date, amount, currency) = parse_balance(value)?;
+                if let Some(opening) = self.opening.take() {
+                    let what = format!("Closing balance of {}", self.account);
+                    self.bean.verify(&what, amount, opening + self.movement);
+                }
+                if tag == "62M" {
+                    return;
+                }
+                let balance = Balance::closing(date, &self.account, amount, &currency);
+                self.bean.add_balance(balance);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[throws]
+pub fn import(contents: &str) -> Bean<'static> {
+    let mut reader = Reader {
+        bean: Bean::new("Assets:Bank"),
+        account: String::new(),
+        currency: String::new(),
+        pending: None,
+        opening: None,
+        movement: Decimal::ZERO,
+    };
+    for (line, tag, value) in fields(contents) {
+        if let Err(e) = reader.read(line, tag, &value) {
+            reader.bean.reject(Reject {
+                line,
+                raw: format!(":{}:{}", tag, value),
+                reason: format!("{:#}", e),
+            });
+        }
+    }
+    reader.flush();
+    reader.bean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, parse_information, Mt940};
+    use crate::lib::{Flow, Format, Rules, Transaction};
+    use fehler::throws;
+
+    type Error = anyhow::Error;
+
+    static MT940: &str = "{1:F01BANKDEFFXXXX0000000000}{4:
+:20:STARTUMSE
+:25:10020030/1234567
+:28C:00001/001
+:60F:C240301EUR1000,00
+:61:2403040304D12,50NTRFNONREF//B1
+:86:166?00SEPA-UEBERWEISUNG?20Strom Maerz?21Kunde 42?32Stadtwer
+ke
+:61:2403100311C500,NTRFINV7
+:86:/NAME/Customer Co/REMI/Invoice 7/
+:61:240331D1,00NCHGNONREF
+:86:Kontofuehrung
+:62F:C240331EUR1486,50
+-}";
+
+    #[test]
+    fn it_parse_information() {
+        assert_eq!(
+            parse_information("166?00SEPA?20Strom ?21Maerz?32Stadt?33werke"),
+            ("Stadtwerke".to_owned(), "Strom Maerz".to_owned())
+        );
+        assert_eq!(
+            parse_information("/NAME/Customer/REMI/Invoice 7/"),
+            ("Customer".to_owned(), "Invoice 7".to_owned())
+        );
+        assert_eq!(
+            parse_information("Kontofuehrung"),
+            (String::new(), "Kontofuehrung".to_owned())
+        );
+        assert_eq!(
+            parse_information("166?aé?20Miete"),
+            (String::new(), "Miete".to_owned())
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_parse_statement_line() {
+        let transaction = Mt940::parse("2312290102RD12,5NTRFREF1//B2", "acct", "EUR")?;
+        assert_eq!(transaction.date()?, "2024-01-02");
+        assert_eq!(transaction.metadata()?, vec![("value_date", "2023-12-29")]);
+        assert_eq!(transaction.flow()?, Flow::Income);
+        assert_eq!(transaction.amount()?.to_string(), "-12.5");
+        assert_eq!(transaction.id()?, "B2");
+        assert!(Mt940::parse("24033D1,00", "acct", "EUR").is_err());
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_statement() {
+        let bean = import(MT940)?;
+        assert!(bean.rejects().is_empty());
+        assert!(bean.warnings().is_empty());
+        let rules = Rules::from_str("[fund]\n\"10020030/1234567\" = \"Assets:Bank:Giro\"")?;
+        assert_eq!(
+            bean.output_with_rules(rules, Format::Beancount)?,
+            r#"2024-03-04 ! "Stadtwerke" "Strom Maerz Kunde 42"
+  trade_id: "B1"
+  value_date: "2024-03-04"
+  Expenses:Unknown 12.50 EUR
+  Assets:Bank:Giro
+2024-03-11 ! "Customer Co" "Invoice 7"
+  trade_id: "INV7"
+  value_date: "2024-03-10"
+  Income:Unknown -500 EUR
+  Assets:Bank:Giro
+2024-03-31 ! "" "Kontofuehrung"
+  value_date: "2024-03-31"
+  Expenses:Unknown 1.00 EUR
+  Assets:Bank:Giro
+2024-04-01 balance Assets:Bank:Giro 1486.50 EUR
+"#
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_reject_malformed_lines() {
+        let bean = import(&MT940.replace(":61:240331D1,00", ":61:240399D1,00"))?;
+        assert_eq!(bean.rejects().len(), 1);
+        assert_eq!(bean.rejects()[0].line, 11);
+    }
+}
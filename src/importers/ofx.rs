@@ -0,0 +1,426 @@
+use super::markup::scan;
+use crate::lib::{Balance, Bean, Flow, Reject, Transaction};
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use encoding_rs::{Encoding, WINDOWS_1252};
+use fehler::throws;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+type Error = anyhow::Error;
+
+/// A `STMTTRN` entry of a bank or credit card statement, fields of the `CURRENCY` and
+/// `ORIGCURRENCY` aggregates are keyed like `CURRENCY/CURSYM`.
+pub struct Ofx {
+    fields: HashMap<String, String>,
+    account: String,
+    currency: String,
+    date: Option<String>,
+}
+
+impl Ofx {
+    fn new(fields: HashMap<String, String>, account: &str, currency: &str) -> Self {
+        let date = fields.get("DTPOSTED").and_then(|s| parse_date(s));
+        Self {
+            fields,
+            account: account.to_owned(),
+            currency: currency.to_owned(),
+            date,
+        }
+    }
+
+    #[throws]
+    fn pick(&self, tag: &str) -> &str {
+        self.fields
+            .get(tag)
+            .map(|s| s.as_str())
+            .ok_or_else(|| anyhow!("Missing {} in {:?}", tag, self.fields))?
+    }
+
+    fn get(&self, tag: &str) -> &str {
+        self.fields.get(tag).map_or("", |s| s.as_str())
+    }
+
+    /// TRNAMT is in `CURRENCY/CURSYM` if present, otherwise in CURDEF of the statement.
+    fn cursym(&self) -> &str {
+        self.get("CURRENCY/CURSYM")
+    }
+
+    #[throws]
+    fn trnamt(&self) -> Decimal {
+        let amount = self.pick("TRNAMT")?;
+        // Some banks write decimal commas
+        amount
+            .replace(',', ".")
+            .parse::<Decimal>()
+            .map_err(|_| anyhow!("Invalid TRNAMT: {}", amount))?
+    }
+}
+
+/// Date of `YYYYMMDDHHMMSS.XXX[gmt offset:tz name]`, only the date part is used.
+fn parse_date(s: &str) -> Option<String> {
+    let date = s
+        .get(..8)
+        .filter(|d| d.chars().all(|c| c.is_ascii_digit()))?;
+    Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+}
+
+impl Transaction for Ofx {
+    #[throws]
+    fn date(&self) -> &str {
+        match &self.date {
+            Some(date) => date,
+            None => self.pick("DTPOSTED")?,
+        }
+    }
+
+    /// NAME is optional in OFX, MEMO is used if it is missing.
+    #[throws]
+    fn payee(&self) -> &str {
+        match self.get("NAME") {
+            "" => self.pick("MEMO")?,
+            name => name,
+        }
+    }
+
+    #[throws]
+    fn fund(&self) -> &str {
+        self.account.as_str()
+    }
+
+    #[throws]
+    fn narration(&self) -> &str {
+        match self.get("NAME") {
+            "" => "",
+            _ => self.get("MEMO"),
+        }
+    }
+
+    #[throws]
+    fn id(&self) -> &str {
+        self.pick("FITID")?
+    }
+
+    #[throws]
+    fn metadata(&self) -> Vec<(&str, &str)> {
+        let mut meta = vec![];
+        let check = self.get("CHECKNUM");
+        if !check.is_empty() {
+            meta.push(("check_number", check));
+        }
+        // TRNAMT is still in CURDEF, the original currency is only informative
+        let original = self.get("ORIGCURRENCY/CURSYM");
+        if !original.is_empty() {
+            meta.push(("original_currency", original));
+        }
+        meta
+    }
+
+    /// TRNAMT is negative for money going out of the account.
+    #[throws]
+    fn amount(&self) -> Decimal {
+        -self.trnamt()?
+    }
+
+    #[throws]
+    fn currency(&self) -> &str {
+        match self.cursym() {
+            "" => &self.currency,
+            currency => currency,
+        }
+    }
+
+    /// CURRATE is the ratio of CURDEF to `CURRENCY/CURSYM`.
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        let rate = self.get("CURRENCY/CURRATE");
+        if self.cursym().is_empty() || rate.is_empty() {
+            return None;
+        }
+        let rate = rate
+            .replace(',', ".")
+            .parse::<Decimal>()
+            .map_err(|_| anyhow!("Invalid CURRATE: {}", rate))?;
+        Some(((self.trnamt()? * rate).abs(), self.currency.as_str()))
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
+        if self.trnamt()?.is_sign_negative() {
+            Flow::Expense
+        } else {
+            Flow::Income
+        }
+    }
+
+    fn source(&self) -> &str {
+        "ofx"
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.fields)
+    }
+}
+
+/// Decode the document by its headers, `CHARSET:1252` of SGML or the XML declaration, otherwise
+/// like CSV statements.
+#[throws]
+pub fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> String {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(512)]).to_uppercase();
+    let encoding = encoding.or_else(|| {
+        if head.contains("CHARSET:1252") || head.contains("ENCODING=\"WINDOWS-1252\"") {
+            Some(WINDOWS_1252)
+        } else {
+            None
+        }
+    });
+    super::csv::decode(bytes, encoding)?
+}
+
+/// `LEDGERBAL` of the statement, the balance at the end of `DTASOF`.
+#[throws]
+fn ledger_balance(fields: &HashMap<String, String>, account: &str, currency: &str) -> Balance {
+    let pick = |tag: &str| {
+        fields
+            .get(tag)
+            .ok_or_else(|| anyhow!("Missing {} of LEDGERBAL", tag))
+    };
+    let date = pick("DTASOF")?;
+    let date = parse_date(date)
+        .and_then(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok())
+        .ok_or_else(|| anyhow!("Invalid DTASOF: {}", date))?;
+    let amount = pick("BALAMT")?;
+    let amount = amount
+        .replace(',', ".")
+        .parse::<Decimal>()
+        .map_err(|_| anyhow!("Invalid BALAMT: {}", amount))?;
+    Balance::closing(date, account, amount, currency)
+}
+
+pub fn is_match(contents: &str) -> bool {
+    contents.contains("OFXHEADER") || contents.contains("<OFX>")
+}
+
+/// Trimmed lines of a statement row, between the start and end lines inclusive.
+fn raw(lines: &[&str], start: usize, end: usize) -> String {
+    lines[start - 1..end.max(start).min(lines.len())]
+        .iter()
+        .map(|s| s.trim())
+        .collect()
+}
+
+#[throws]
+pub fn import(contents: &str) -> Bean<'static> {
+    let mut bean = Bean::new("Assets:Bank");
+    let mut account = String::new();
+    let mut currency = "USD".to_owned();
+    let mut transaction: Option<(usize, HashMap<String, String>)> = None;
+    let mut balance: Option<(usize, HashMap<String, String>)> = None;
+    // `CURRENCY` or `ORIGCURRENCY` aggregate of the transaction
+    let mut aggregate: Option<&str> = None;
+    let lines = contents.lines().collect::<Vec<_>>();
+    for element in scan(contents) {
+        // SGML allows omitting the end tags of transactions, a transaction also ends at the next one
+        // or the end of the list
+        let end = match (element.tag, element.closing) {
+            ("STMTTRN", true) => Some(element.line),
+            ("STMTTRN", false) | ("BANKTRANLIST", true) => Some(element.line - 1),
+            _ => None,
+        };
+        if let Some(end) = end {
+            if let Some((line, fields)) = transaction.take() {
+                let raw = raw(&lines, line, end);
+                bean.add_row(line, &raw, Ofx::new(fields, &account, &currency));
+            }
+        }
+        match (element.tag, element.closing, &mut transaction) {
+            ("STMTTRN", false, _) => {
+                transaction = Some((element.line, HashMap::new()));
+                aggregate = None;
+            }
+            ("CURRENCY" | "ORIGCURRENCY", closing, Some(_)) => {
+                aggregate = if closing { None } else { Some(element.tag) };
+            }
+            (tag, false, Some((_, fields))) if !element.text.is_empty() => {
+                let key = match aggregate {
+                    Some(aggregate) => format!("{}/{}", aggregate, tag),
+                    None => tag.to_owned(),
+                };
+                fields.insert(key, element.text);
+            }
+            ("LEDGERBAL", false, None) => balance = Some((element.line, HashMap::new())),
+            ("LEDGERBAL", true, None) => {
+                if let Some((line, fields)) = balance.take() {
+                    match ledger_balance(&fields, &account, &currency) {
+                        Ok(balance) => bean.add_balance(balance),
+                        Err(e) => bean.reject(Reject {
+                            line,
+                            raw: raw(&lines, line, element.line),
+                            reason: format!("{:#}", e),
+                        }),
+                    }
+                }
+            }
+            (tag, false, None) if !element.text.is_empty() => match (&mut balance, tag) {
+                (Some((_, fields)), _) => {
+                    fields.insert(tag.to_owned(), element.text);
+                }
+                (None, "ACCTID") => account = element.text,
+                (None, "CURDEF") => currency = element.text,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    if let Some((line, fields)) = transaction {
+        let raw = raw(&lines, line, lines.len());
+        bean.add_row(line, &raw, Ofx::new(fields, &account, &currency));
+    }
+    bean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, Ofx};
+    use crate::lib::{Flow, Format, Rules, Transaction};
+    use fehler::throws;
+    use std::collections::HashMap;
+
+    type Error = anyhow::Error;
+
+    static SGML: &str = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+CHARSET:1252
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<CURDEF>USD
+<BANKACCTFROM><BANKID>121000248<ACCTID>000123456<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20240315120000.000[-5:EST]
+<TRNAMT>-12.50
+<FITID>20240315001
+<NAME>COFFEE &amp; CO
+<MEMO>POS PURCHASE
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>20240316
+<TRNAMT>1000.00
+<FITID>20240316001
+<MEMO>PAYROLL
+</STMTTRN>
+</BANKTRANLIST>
+<LEDGERBAL><BALAMT>987.50<DTASOF>20240331</LEDGERBAL>
+<AVAILBAL><BALAMT>900.00<DTASOF>20240331</AVAILBAL>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    #[test]
+    #[throws]
+    fn get_transaction() {
+        let fields = [
+            ("DTPOSTED", "20240315120000.000[-5:EST]"),
+            ("TRNAMT", "-12.50"),
+            ("FITID", "1"),
+            ("NAME", "COFFEE"),
+            ("MEMO", "POS PURCHASE"),
+            ("CURRENCY/CURSYM", "EUR"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<HashMap<_, _>>();
+        let transaction = Ofx::new(fields, "000123456", "USD");
+        assert_eq!(transaction.date()?, "2024-03-15");
+        assert_eq!(transaction.payee()?, "COFFEE");
+        assert_eq!(transaction.narration()?, "POS PURCHASE");
+        assert_eq!(transaction.fund()?, "000123456");
+        assert_eq!(transaction.id()?, "1");
+        assert_eq!(transaction.amount()?.to_string(), "12.50");
+        assert_eq!(transaction.currency()?, "EUR");
+        assert_eq!(transaction.flow()?, Flow::Expense);
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_sgml() {
+        let bean = import(SGML)?;
+        assert!(bean.rejects().is_empty());
+        let output = bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?;
+        assert!(output.contains("2024-03-15 ! \"COFFEE & CO\" \"POS PURCHASE\""));
+        assert!(output.contains("12.50 USD\n  Assets:Bank"));
+        assert!(output.contains("2024-03-16 ! \"PAYROLL\" \"\""));
+        assert!(output.contains("-1000.00 USD"));
+        assert!(output.ends_with("2024-04-01 balance Assets:Bank 987.50 USD\n"));
+        assert!(!output.contains("900.00"));
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_xml() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220"?>
+<OFX><CREDITCARDMSGSRSV1><CCSTMTTRNRS><CCSTMTRS>
+<CURDEF>EUR</CURDEF>
+<CCACCTFROM><ACCTID>4111</ACCTID></CCACCTFROM>
+<BANKTRANLIST>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240301</DTPOSTED><TRNAMT>-5</TRNAMT>
+<FITID>A</FITID><NAME>SHOP</NAME></STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>bad</DTPOSTED><TRNAMT>-5</TRNAMT>
+<FITID>B</FITID><NAME>SHOP</NAME></STMTTRN>
+</BANKTRANLIST></CCSTMTRS></CCSTMTTRNRS></CREDITCARDMSGSRSV1></OFX>
+"#;
+        let bean = import(xml)?;
+        assert_eq!(bean.rejects().len(), 1);
+        assert_eq!(bean.rejects()[0].line, 9);
+        let output = bean.output_with_rules(
+            Rules::from_str("[fund]\n\"4111\" = \"Liabilities:Card\"")?,
+            Format::Beancount,
+        )?;
+        assert_eq!(
+            output,
+            "2024-03-01 ! \"SHOP\" \"\"\n  trade_id: \"A\"\n  Expenses:Unknown 5 EUR\n  Liabilities:Card\n"
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_currency() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<OFX><BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<CURDEF>USD</CURDEF>
+<BANKACCTFROM><ACCTID>1234</ACCTID></BANKACCTFROM>
+<BANKTRANLIST>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240301</DTPOSTED><TRNAMT>-10.80</TRNAMT>
+<FITID>A</FITID><NAME>CAFE</NAME>
+<ORIGCURRENCY><CURRATE>1.08</CURRATE><CURSYM>EUR</CURSYM></ORIGCURRENCY></STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240302</DTPOSTED><TRNAMT>-10</TRNAMT>
+<FITID>B</FITID><NAME>SHOP</NAME>
+<CURRENCY><CURRATE>1.08</CURRATE><CURSYM>EUR</CURSYM></CURRENCY></STMTTRN>
+</BANKTRANLIST></STMTRS></STMTTRNRS></BANKMSGSRSV1></OFX>
+"#;
+        let bean = import(xml)?;
+        assert!(bean.rejects().is_empty());
+        let output = bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?;
+        // TRNAMT of ORIGCURRENCY is still in CURDEF
+        assert!(output.contains("  original_currency: \"EUR\"\n  Expenses:Unknown 10.80 USD\n"));
+        // TRNAMT of CURRENCY is in CURSYM, converted to CURDEF by CURRATE
+        assert!(output.contains("  Expenses:Unknown 10 EUR @@ 10.80 USD\n"));
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_unclosed_transactions() {
+        let sgml = SGML.replace("</STMTTRN>\n", "");
+        let bean = import(&sgml)?;
+        assert!(bean.rejects().is_empty());
+        let output = bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?;
+        assert!(output.contains("2024-03-15 ! \"COFFEE & CO\" \"POS PURCHASE\""));
+        assert!(output.contains("2024-03-16 ! \"PAYROLL\" \"\""));
+        assert!(output.ends_with("2024-04-01 balance Assets:Bank 987.50 USD\n"));
+    }
+}
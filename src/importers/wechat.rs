@@ -1,61 +1,110 @@
-pub use super::csv::{pick, Parser};
-use crate::lib::{Bean, Flow, Transaction};
-use anyhow;
-use csv::StringRecord;
+use super::csv::{sniff, split_line, Parser, Record};
+use crate::lib::{cached_regex, Bean, Flow, Transaction};
 use fehler::throws;
-use std::path::PathBuf;
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
 
-pub struct Wechat(StringRecord);
+const DATE: &str = "交易时间";
+const TRADE_TYPE: &str = "交易类型";
+const PAYEE: &str = "交易对方";
+const COMMODITY: &str = "商品";
+const FLOW: &str = "收/支";
+const AMOUNT: &str = "金额(元)";
+const FUND: &str = "支付方式";
+const STATUS: &str = "当前状态";
+const TRADE_ID: &str = "交易单号";
+const REMARK: &str = "备注";
+
+const BANNER: &str = "微信支付账单明细";
+
+/// Summary in the preamble, e.g. `共2笔记录` and `收入：1笔 10.00元`.
+static ROWS: &str = r"^共(\d+)笔记录";
+static TOTAL: &str = r"^(收入|支出|中性交易)：(\d+)笔\s*([\d,.]+)元";
+
+fn rows_line() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    cached_regex(&CELL, ROWS)
+}
+
+fn total_line() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    cached_regex(&CELL, TOTAL)
+}
+
+static COLUMNS: &[&str] = &[
+    DATE, TRADE_TYPE, PAYEE, COMMODITY, FLOW, AMOUNT, FUND, STATUS, TRADE_ID, REMARK,
+];
+
+pub struct Wechat(Record);
 
 type Error = anyhow::Error;
 
 impl<'a> Wechat {
-    pub fn new(record: StringRecord) -> Self {
+    pub fn new(record: Record) -> Self {
         Self(record)
     }
 
     #[throws]
-    fn pick<F, T>(&'a self, name: &str, i: usize, transform: F) -> T
+    fn pick<F, T>(&'a self, name: &str, transform: F) -> T
     where
         F: Fn(&'a str) -> Option<T>,
     {
-        pick(&self.0, name, i, transform)?
+        self.0.pick(name, transform)?
     }
 
     #[throws]
     fn status(&self) -> &str {
-        self.pick("status", 7, Self::default_transform)?
+        self.pick(STATUS, Self::default_transform)?
     }
 
     #[throws]
     fn trade_type(&self) -> &str {
-        self.pick("trade_type", 1, Self::default_transform)?
+        self.pick(TRADE_TYPE, Self::default_transform)?
     }
 
     #[throws]
     fn remark(&self) -> &str {
-        self.pick("remark", 10, Self::default_transform)?
+        self.pick(REMARK, Self::default_transform)?
     }
 
     fn default_transform(s: &str) -> Option<&str> {
         Some(s)
     }
+
+    /// Kind and amount of the row in the summary, by `收/支` regardless of the status.
+    fn summary_entry(&self) -> Option<(&'static str, Decimal)> {
+        let flow = self.0.get(FLOW)?;
+        let amount = self.0.get(AMOUNT)?.trim_start_matches('¥').parse().ok()?;
+        let kind = match flow {
+            "收入" => "收入",
+            "支出" => "支出",
+            _ => "中性交易",
+        };
+        Some((kind, amount))
+    }
 }
 
 impl Transaction for Wechat {
     #[throws]
     fn date(&self) -> &str {
-        self.pick("date", 0, |s| s.split_whitespace().next())?
+        self.pick(DATE, |s| s.split_whitespace().next())?
+    }
+
+    #[throws]
+    fn time(&self) -> &str {
+        self.pick(DATE, |s| Some(s.split_whitespace().nth(1).unwrap_or("")))?
     }
 
     #[throws]
     fn payee(&self) -> &str {
-        self.pick("payee", 2, Self::default_transform)?
+        self.pick(PAYEE, Self::default_transform)?
     }
 
     #[throws]
     fn narration(&self) -> &str {
-        let commodity = self.pick("narration", 3, Self::default_transform)?;
+        let commodity = self.pick(COMMODITY, Self::default_transform)?;
         if commodity == "/" {
             let remark = self.remark()?;
             if remark == "/" {
@@ -68,14 +117,19 @@ impl Transaction for Wechat {
         }
     }
 
+    #[throws]
+    fn id(&self) -> &str {
+        self.pick(TRADE_ID, Self::default_transform)?
+    }
+
     #[throws]
     fn fund(&self) -> &str {
-        self.pick("fund", 6, Self::default_transform)?
+        self.pick(FUND, Self::default_transform)?
     }
 
     #[throws]
-    fn amount(&self) -> f32 {
-        let amount: f32 = self.pick("amount", 5, |s| s.trim_start_matches('¥').parse().ok())?;
+    fn amount(&self) -> Decimal {
+        let amount: Decimal = self.pick(AMOUNT, |s| s.trim_start_matches('¥').parse().ok())?;
         match self.flow()? {
             Flow::Income => -amount,
             _ => amount,
@@ -83,8 +137,8 @@ impl Transaction for Wechat {
     }
 
     #[throws]
-    fn flow(&self) -> Flow {
-        let flow = self.pick("flow", 4, Self::default_transform)?;
+    fn flow(&self) -> Flow<'_> {
+        let flow = self.pick(FLOW, Self::default_transform)?;
         let status = self.status()?;
         match status {
             "已全额退款" | "提现已到账" => Flow::Unknown(status),
@@ -101,25 +155,104 @@ impl Transaction for Wechat {
         meta
     }
 
+    fn source(&self) -> &str {
+        "wechat"
+    }
+
     fn display(&self) -> String {
         format!("{:?}", self.0)
     }
 }
 
+pub fn is_match(contents: &str) -> bool {
+    sniff(contents, BANNER, COLUMNS)
+}
+
+/// Row count and `(kind, count, total)` in the preamble.
+fn parse_summary(contents: &str) -> (Option<usize>, Vec<(String, usize, Decimal)>) {
+    let mut rows = None;
+    let mut totals = vec![];
+    for line in contents.lines().take_while(|line| !line.starts_with(DATE)) {
+        let cell = match split_line(line, b',') {
+            Ok(record) => record.get(0).unwrap_or("").to_owned(),
+            Err(_) => continue,
+        };
+        if let Some(captures) = rows_line().captures(&cell) {
+            rows = captures[1].parse().ok();
+        } else if let Some(captures) = total_line().captures(&cell) {
+            let count = captures[2].parse().ok();
+            let total = captures[3].replace(',', "").parse().ok();
+            if let (Some(count), Some(total)) = (count, total) {
+                totals.push((captures[1].to_owned(), count, total));
+            }
+        }
+    }
+    (rows, totals)
+}
+
+/// Warn if the imported rows don't match the counts and totals of the summary.
+fn verify_summary(bean: &mut Bean, contents: &str, imported: &BTreeMap<&str, (usize, Decimal)>) {
+    let (rows, totals) = parse_summary(contents);
+    if let Some(rows) = rows {
+        let count = imported.values().map(|(count, _)| count).sum();
+        bean.verify("Row count", rows, count);
+    }
+    for (kind, count, total) in totals {
+        let (imported_count, imported_total) = imported
+            .get(kind.as_str())
+            .copied()
+            .unwrap_or((0, Decimal::ZERO));
+        bean.verify(&format!("{} count", kind), count, imported_count);
+        bean.verify(&format!("{} total", kind), total, imported_total);
+    }
+}
+
 #[throws]
-pub fn import(input: PathBuf, edit: bool) -> String {
-    let parser = Parser::new(input, 16, edit);
-    let bean = Bean::new("Assets:Wechat");
-    parser.output(bean, Wechat::new)?
+pub fn import(contents: &str) -> Bean<'static> {
+    let parser = Parser::new(COLUMNS);
+    let mut bean = Bean::new("Assets:Wechat");
+    let mut imported = BTreeMap::new();
+    for row in parser.parse(contents)? {
+        match row {
+            Ok(row) => {
+                let transaction = Wechat::new(row.record);
+                let entry = transaction.summary_entry();
+                if bean.add_row(row.line, &row.raw, transaction).is_some() {
+                    if let Some((kind, amount)) = entry {
+                        let (count, total) = imported.entry(kind).or_insert((0, Decimal::ZERO));
+                        *count += 1;
+                        *total += amount;
+                    }
+                }
+            }
+            Err(reject) => bean.reject(reject),
+        }
+    }
+    verify_summary(&mut bean, contents, &imported);
+    bean
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Wechat;
+    use super::{import, Wechat};
     use crate::lib::Transaction;
     use crate::test_helpers::gen_record;
     use fehler::throws;
 
+    static HEADERS: &[&str] = &[
+        "交易时间",
+        "交易类型",
+        "交易对方",
+        "商品",
+        "收/支",
+        "金额(元)",
+        "支付方式",
+        "当前状态",
+        "交易单号",
+        "商户单号",
+        "备注",
+    ];
+
     #[derive(Default)]
     pub struct Trans<'a> {
         date: &'a str,
@@ -136,7 +269,7 @@ mod tests {
     }
     impl<'a> Trans<'a> {
         pub fn as_string(&self) -> String {
-            vec![
+            [
                 self.date,
                 self.trade_type,
                 self.payee,
@@ -172,9 +305,10 @@ mod tests {
             date: "2020-03-30 18:46:56",
             ..Trans::default()
         };
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
-        assert_eq!(wechat.date()?, "2020-03-30")
+        assert_eq!(wechat.date()?, "2020-03-30");
+        assert_eq!(wechat.time()?, "18:46:56")
     }
 
     #[test]
@@ -184,7 +318,7 @@ mod tests {
             fund: "CCB",
             ..Trans::default()
         };
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
         assert_eq!(wechat.fund()?, "CCB")
     }
@@ -196,7 +330,7 @@ mod tests {
             flow: "unknownflow",
             ..Trans::default()
         };
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
         assert_eq!(wechat.metadata()?, vec![("unknown_flow", t.flow)])
     }
@@ -209,7 +343,7 @@ mod tests {
             status: "已全额退款",
             ..Trans::default()
         };
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
         assert_eq!(wechat.metadata()?, vec![("unknown_flow", t.status)])
     }
@@ -218,7 +352,7 @@ mod tests {
     #[throws]
     fn mark_unknown_for_with_draw() {
         let t = gen_with_draw();
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
         assert_eq!(wechat.metadata()?, vec![("unknown_flow", t.status)])
     }
@@ -227,8 +361,50 @@ mod tests {
     #[throws]
     fn get_narration_with_draw() {
         let t = gen_with_draw();
-        let r = gen_record(&t.as_string())?;
+        let r = gen_record(HEADERS, &t.as_string())?;
         let wechat = Wechat::new(r);
         assert_eq!(wechat.narration()?, t.remark)
     }
+
+    #[test]
+    #[throws]
+    fn get_income_amount() {
+        let t = Trans {
+            flow: "收入",
+            amount: "¥12.30",
+            ..Trans::default()
+        };
+        let r = gen_record(HEADERS, &t.as_string())?;
+        let wechat = Wechat::new(r);
+        assert_eq!(wechat.amount()?.to_string(), "-12.30")
+    }
+
+    #[test]
+    #[throws]
+    fn it_verify_summary() {
+        let statement = |summary: &str| {
+            format!(
+                "微信支付账单明细,,,,,,,,
+共3笔记录,,,,,,,,
+{},,,,,,,,
+----------------------微信支付账单明细列表--------------------,,,,,,,,
+交易时间,交易类型,交易对方,商品,收/支,金额(元),支付方式,当前状态,交易单号,商户单号,备注
+2020-03-30 18:46:56,商户消费,Starbucks,咖啡,支出,¥25.50,零钱,支付成功,1,/,/
+2020-03-31 18:46:56,商户消费,Starbucks,咖啡,支出,¥4.50,零钱,已全额退款,2,/,/
+2020-03-02 09:00:00,微信红包,Friend,/,收入,¥10.00,/,已存入零钱,3,/,/
+",
+                summary
+            )
+        };
+        let bean = import(&statement("收入：1笔 10.00元\n支出：2笔 30.00元"))?;
+        assert!(bean.warnings().is_empty());
+        let bean = import(&statement("收入：1笔 10.00元\n\"支出：3笔 1,030.00元\""))?;
+        assert_eq!(
+            bean.warnings(),
+            [
+                "支出 count is 3 in the statement, but 2 imported",
+                "支出 total is 1030.00 in the statement, but 30.00 imported"
+            ]
+        );
+    }
 }
@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+use calamine::{open_workbook_from_rs, Data, DataType, Reader, Xlsx};
+use csv::Writer;
+use fehler::throws;
+use std::io::Cursor;
+
+type Error = anyhow::Error;
+
+fn cell_to_string(cell: &Data) -> String {
+    match cell {
+        Data::Empty => String::new(),
+        Data::DateTime(_) => cell
+            .as_datetime()
+            .map(|datetime| datetime.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| cell.to_string()),
+        _ => cell.to_string(),
+    }
+}
+
+/// Convert the first worksheet to CSV, so it goes through the same parsing as CSV statements.
+#[throws]
+pub fn to_csv(bytes: &[u8]) -> String {
+    let mut workbook: Xlsx<_> = open_workbook_from_rs(Cursor::new(bytes))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| anyhow!("No worksheet found in the workbook"))??;
+    let mut writer = Writer::from_writer(vec![]);
+    for row in range.rows() {
+        writer.write_record(row.iter().map(cell_to_string))?;
+    }
+    String::from_utf8(writer.into_inner()?)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cell_to_string, to_csv};
+    use crate::importers::wechat;
+    use crate::lib::{Format, Rules};
+    use calamine::Data;
+    use fehler::throws;
+
+    type Error = anyhow::Error;
+
+    /// A WeChat statement with `交易时间` as DateTime cells.
+    static WECHAT: &[u8] = include_bytes!("testdata/wechat.xlsx");
+
+    #[test]
+    fn it_convert_cells() {
+        assert_eq!(cell_to_string(&Data::Empty), "");
+        assert_eq!(cell_to_string(&Data::String("¥25.50".to_owned())), "¥25.50");
+        assert_eq!(cell_to_string(&Data::Float(25.5)), "25.5");
+    }
+
+    #[test]
+    #[throws]
+    fn it_import_wechat_xlsx() {
+        let csv = to_csv(WECHAT)?;
+        assert!(csv.starts_with("微信支付账单明细,,,,,,,,,,\n"));
+        assert!(csv.contains("\n2020-03-30 18:46:56,商户消费,Starbucks,"));
+        let bean = wechat::import(&csv)?;
+        assert!(bean.rejects().is_empty());
+        assert!(bean.warnings().is_empty());
+        let output = bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?;
+        assert!(output.starts_with("2020-03-02 ! \"Friend\" \"微信红包\""));
+        assert!(output.contains("  time: \"09:00:00\"\n  Income:Unknown -10.00 CNY\n"));
+        assert!(output.contains("2020-03-30 ! \"Starbucks\" \"咖啡\""));
+        assert!(output.contains("  time: \"18:46:56\"\n  Expenses:Unknown 25.50 CNY\n"));
+    }
+}
@@ -0,0 +1,198 @@
+use super::{cached_regex, Balance, Entry, Writer};
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use fehler::{throw, throws};
+use regex::Regex;
+use std::sync::OnceLock;
+
+type Error = anyhow::Error;
+
+// Non-ASCII characters are allowed in account names and keys since beancount 2.3.
+static ACCOUNT: &str = r"^([A-Z]|[^\x00-\x7F])([A-Za-z0-9-]|[^\x00-\x7F])*(:([A-Z0-9]|[^\x00-\x7F])([A-Za-z0-9-]|[^\x00-\x7F])*)+$";
+static KEY: &str = r"^[a-z][a-zA-Z0-9_-]*$";
+static TAG: &str = r"^[A-Za-z0-9_/.-]+$";
+static CURRENCY: &str = r"^[A-Z]([A-Z0-9'._-]{0,22}[A-Z0-9])?$";
+static DATE: &str = r"^\d{4}-\d{2}-\d{2}$";
+static TIME: &str = r"^\d{2}:\d{2}:\d{2}$";
+
+#[throws]
+pub fn validate_account(account: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, ACCOUNT).is_match(account) {
+        throw!(anyhow!("Invalid account name: {}", account));
+    }
+}
+
+#[throws]
+pub fn validate_key(key: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, KEY).is_match(key) {
+        throw!(anyhow!("Invalid metadata key: {}", key));
+    }
+}
+
+#[throws]
+pub fn validate_tag(tag: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, TAG).is_match(tag) {
+        throw!(anyhow!("Invalid tag: {}", tag));
+    }
+}
+
+#[throws]
+pub fn validate_currency(currency: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, CURRENCY).is_match(currency) {
+        throw!(anyhow!("Invalid currency: {}", currency));
+    }
+}
+
+#[throws]
+pub fn validate_date(date: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, DATE).is_match(date) {
+        throw!(anyhow!("Invalid date: {}", date));
+    }
+}
+
+#[throws]
+pub fn validate_time(time: &str) {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    if !cached_regex(&CELL, TIME).is_match(time) {
+        throw!(anyhow!("Invalid time: {}", time));
+    }
+}
+
+/// Quote a string, escaping backslashes and double quotes. Line breaks are replaced with spaces
+/// to keep one directive per line.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' | '\n' => quoted.push(' '),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write the entry in beancount syntax, refusing anything bean-check would reject. A missing payee
+/// account is written as a placeholder for the user to fill in.
+#[throws]
+pub fn write(entry: &Entry) -> String {
+    validate_date(entry.date)?;
+    let mut output = format!(
+        "{} {} {} {}",
+        entry.date,
+        entry.flag,
+        quote(entry.alias.unwrap_or(entry.payee)),
+        quote(entry.narration)
+    );
+    for tag in &entry.tags {
+        validate_tag(tag)?;
+        output.push_str(&format!(" #{}", tag));
+    }
+    for (key, value) in &entry.metadata {
+        validate_key(key)?;
+        output.push_str(&format!("\n  {}: {}", key, quote(value)));
+    }
+    let account = entry.posting_account();
+    validate_account(account)?;
+    validate_currency(entry.currency)?;
+    output.push_str(&format!(
+        "\n  {} {} {}",
+        account, entry.amount, entry.currency
+    ));
+    if let Some((total, currency)) = entry.price {
+        validate_currency(currency)?;
+        output.push_str(&format!(" @@ {} {}", total, currency));
+    }
+    validate_account(entry.fund_account)?;
+    output.push_str(&format!("\n  {}\n", entry.fund_account));
+    output
+}
+
+/// Beancount checks balances before the transactions of the day, so the balance at the end of a
+/// date is asserted on the next day.
+fn balance_date(balance: &Balance) -> NaiveDate {
+    balance.date.succ_opt().unwrap_or(balance.date)
+}
+
+/// Write the balance assertion of the account.
+#[throws]
+pub fn write_balance(balance: &Balance, account: &str) -> String {
+    validate_account(account)?;
+    validate_currency(&balance.currency)?;
+    format!(
+        "{} balance {} {} {}\n",
+        balance_date(balance).format("%Y-%m-%d"),
+        account,
+        balance.amount,
+        balance.currency
+    )
+}
+
+#[throws]
+pub fn write_open(date: &str, account: &str) -> String {
+    validate_date(date)?;
+    validate_account(account)?;
+    format!("{} open {}\n", date, account)
+}
+
+/// Beancount syntax, the default output.
+pub struct Beancount;
+
+impl Writer for Beancount {
+    #[throws]
+    fn write(&self, entry: &Entry) -> String {
+        write(entry)?
+    }
+
+    #[throws]
+    fn write_balance(&self, balance: &Balance, account: &str) -> String {
+        write_balance(balance, account)?
+    }
+
+    fn balance_date(&self, balance: &Balance) -> NaiveDate {
+        balance_date(balance)
+    }
+
+    #[throws]
+    fn write_open(&self, date: &str, account: &str) -> String {
+        write_open(date, account)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, validate_account, validate_key};
+
+    #[test]
+    fn it_quote_strings() {
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(quote("a\nb"), r#""a b""#);
+    }
+
+    #[test]
+    fn it_validate_account() {
+        assert!(validate_account("Assets:Wechat").is_ok());
+        assert!(validate_account("Expenses:餐饮:2020").is_ok());
+        assert!(validate_account("Assets").is_err());
+        assert!(validate_account("Assets:wechat").is_err());
+        assert!(validate_account("Assets:We chat").is_err());
+    }
+
+    #[test]
+    fn it_validate_key() {
+        assert!(validate_key("trade_id").is_ok());
+        assert!(validate_key("unknownFlow-2").is_ok());
+        assert!(validate_key("TradeId").is_err());
+        assert!(validate_key("trade id").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use super::{Balance, Entry, Writer};
+use anyhow::anyhow;
+use fehler::{throw, throws};
+use serde_json::{json, Map, Value};
+
+type Error = anyhow::Error;
+
+/// JSON Lines, one object per transaction, balance or open directive. Amounts are strings to keep
+/// the precision.
+pub struct Json;
+
+/// Normalized CSV with a header row, balances only fill the date, account, amount and currency, and
+/// open directives only the date and account. Tags are separated by spaces and metadata is a JSON
+/// object.
+pub struct Csv;
+
+static CSV_HEADER: [&str; 18] = [
+    "type",
+    "date",
+    "flag",
+    "payee",
+    "alias",
+    "narration",
+    "tags",
+    "metadata",
+    "account",
+    "fund_account",
+    "amount",
+    "currency",
+    "price",
+    "price_currency",
+    "flow",
+    "source",
+    "line",
+    "raw",
+];
+
+fn metadata(entry: &Entry) -> Map<String, Value> {
+    entry
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::from(*value)))
+        .collect()
+}
+
+impl Writer for Json {
+    #[throws]
+    fn write(&self, entry: &Entry) -> String {
+        let metadata = metadata(entry);
+        let price = entry.price.map(|(total, currency)| {
+            json!({
+                "amount": total.to_string(),
+                "currency": currency,
+            })
+        });
+        let object = json!({
+            "type": "transaction",
+            "date": entry.date,
+            "flag": entry.flag,
+            "payee": entry.payee,
+            "alias": entry.alias,
+            "narration": entry.narration,
+            "tags": entry.tags,
+            "metadata": metadata,
+            "account": entry.account,
+            "fund_account": entry.fund_account,
+            "amount": entry.amount.to_string(),
+            "currency": entry.currency,
+            "price": price,
+            "flow": entry.flow.name(),
+            "source": entry.source,
+            "row": {
+                "line": entry.line,
+                "raw": entry.raw,
+            },
+        });
+        format!("{}\n", serde_json::to_string(&object)?)
+    }
+
+    #[throws]
+    fn write_balance(&self, balance: &Balance, account: &str) -> String {
+        let object = json!({
+            "type": "balance",
+            "date": balance.date.format("%Y-%m-%d").to_string(),
+            "account": account,
+            "amount": balance.amount.to_string(),
+            "currency": balance.currency,
+        });
+        format!("{}\n", serde_json::to_string(&object)?)
+    }
+
+    #[throws]
+    fn write_open(&self, date: &str, account: &str) -> String {
+        let object = json!({
+            "type": "open",
+            "date": date,
+            "account": account,
+        });
+        format!("{}\n", serde_json::to_string(&object)?)
+    }
+}
+
+/// A row of the named columns in the order of the header, the other columns are left empty.
+#[throws]
+fn csv_row(columns: &[(&str, &str)]) -> String {
+    if let Some((name, _)) = columns.iter().find(|(name, _)| !CSV_HEADER.contains(name)) {
+        throw!(anyhow!("Unknown CSV column: {}", name));
+    }
+    let fields = CSV_HEADER.iter().map(|header| {
+        columns
+            .iter()
+            .find(|(name, _)| name == header)
+            .map_or("", |(_, value)| *value)
+    });
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(fields)?;
+    String::from_utf8(writer.into_inner()?)?
+}
+
+impl Writer for Csv {
+    fn header(&self) -> String {
+        CSV_HEADER.join(",") + "\n"
+    }
+
+    #[throws]
+    fn write(&self, entry: &Entry) -> String {
+        let metadata = serde_json::to_string(&metadata(entry))?;
+        let (price, price_currency) = match entry.price {
+            Some((total, currency)) => (total.to_string(), currency),
+            None => (String::new(), ""),
+        };
+        csv_row(&[
+            ("type", "transaction"),
+            ("date", entry.date),
+            ("flag", entry.flag),
+            ("payee", entry.payee),
+            ("alias", entry.alias.unwrap_or("")),
+            ("narration", entry.narration),
+            ("tags", &entry.tags.join(" ")),
+            ("metadata", &metadata),
+            ("account", entry.account.unwrap_or("")),
+            ("fund_account", entry.fund_account),
+            ("amount", &entry.amount.to_string()),
+            ("currency", entry.currency),
+            ("price", &price),
+            ("price_currency", price_currency),
+            ("flow", entry.flow.name()),
+            ("source", entry.source),
+            ("line", &entry.line.to_string()),
+            ("raw", entry.raw),
+        ])?
+    }
+
+    #[throws]
+    fn write_balance(&self, balance: &Balance, account: &str) -> String {
+        csv_row(&[
+            ("type", "balance"),
+            ("date", &balance.date.format("%Y-%m-%d").to_string()),
+            ("account", account),
+            ("amount", &balance.amount.to_string()),
+            ("currency", &balance.currency),
+        ])?
+    }
+
+    #[throws]
+    fn write_open(&self, date: &str, account: &str) -> String {
+        csv_row(&[("type", "open"), ("date", date), ("account", account)])?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_row, Csv, Json};
+    use crate::lib::{Balance, Entry, Flow, Writer};
+    use chrono::NaiveDate;
+    use fehler::throws;
+    use rust_decimal::Decimal;
+    use serde_json::Value;
+
+    type Error = anyhow::Error;
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            date: "2020-04-01",
+            flag: "*",
+            payee: "Shop",
+            alias: Some("Coffee"),
+            narration: "latte, large",
+            tags: vec!["food"],
+            metadata: vec![("trade_id", "1")],
+            account: Some("Expenses:Food"),
+            amount: Decimal::new(1250, 2),
+            currency: "USD",
+            price: Some((Decimal::new(8800, 2), "CNY")),
+            fund_account: "Assets:Wechat",
+            flow: Flow::Expense,
+            source: "wechat",
+            line: 18,
+            raw: "1,Shop,\"latte, large\"",
+        }
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_json() {
+        let output = Json.write(&entry())?;
+        assert!(output.ends_with('\n'));
+        let value: Value = serde_json::from_str(&output)?;
+        assert_eq!(value["type"], "transaction");
+        assert_eq!(value["alias"], "Coffee");
+        assert_eq!(value["amount"], "12.50");
+        assert_eq!(value["price"]["amount"], "88.00");
+        assert_eq!(value["metadata"]["trade_id"], "1");
+        assert_eq!(value["flow"], "expense");
+        assert_eq!(value["row"]["line"], 18);
+        assert_eq!(value["row"]["raw"], "1,Shop,\"latte, large\"");
+        let entry = Entry {
+            account: None,
+            price: None,
+            ..entry()
+        };
+        let value: Value = serde_json::from_str(&Json.write(&entry)?)?;
+        assert!(value["account"].is_null());
+        assert!(value["price"].is_null());
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_csv() {
+        assert!(Csv.header().starts_with("type,date,flag,payee,"));
+        assert_eq!(
+            Csv.write(&entry())?,
+            "transaction,2020-04-01,*,Shop,Coffee,\"latte, large\",food,\"{\"\"trade_id\"\":\"\"1\"\"}\",Expenses:Food,\
+             Assets:Wechat,12.50,USD,88.00,CNY,expense,wechat,18,\"1,Shop,\"\"latte, large\"\"\"\n"
+        );
+        let balance = Balance {
+            date: NaiveDate::from_ymd_opt(2020, 4, 2).unwrap(),
+            fund: "1234".to_owned(),
+            amount: Decimal::new(100, 0),
+            currency: "CNY".to_owned(),
+        };
+        assert_eq!(
+            Csv.write_balance(&balance, "Assets:Bank")?,
+            "balance,2020-04-02,,,,,,,Assets:Bank,,100,CNY,,,,,,\n"
+        );
+        assert_eq!(
+            Csv.write_open("2020-04-01", "Assets:Bank")?,
+            "open,2020-04-01,,,,,,,Assets:Bank,,,,,,,,,\n"
+        );
+        assert!(csv_row(&[("kind", "open")]).is_err());
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_csv_metadata_as_json() {
+        let entry = Entry {
+            metadata: vec![("note", "a; b: c"), ("trade_id", "1")],
+            ..entry()
+        };
+        let output = Csv.write(&entry)?;
+        let record = csv::Reader::from_reader((Csv.header() + &output).as_bytes())
+            .records()
+            .next()
+            .unwrap()?;
+        let metadata: Value = serde_json::from_str(&record[7])?;
+        assert_eq!(metadata["note"], "a; b: c");
+        assert_eq!(metadata["trade_id"], "1");
+    }
+}
@@ -0,0 +1,211 @@
+use super::{Balance, Entry, Writer};
+use anyhow::anyhow;
+use fehler::{throw, throws};
+
+type Error = anyhow::Error;
+
+/// Journal syntax of ledger-cli and hledger. `!` flags mark pending transactions, metadata and tags
+/// are written as comments.
+pub struct Journal {
+    pub hledger: bool,
+}
+
+/// Keep one line per directive.
+fn one_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+#[throws]
+fn validate_account(account: &str) {
+    if account.trim().is_empty()
+        || account.trim() != account
+        || account.contains("  ")
+        || account.contains('\t')
+        || account.contains(';')
+    {
+        throw!(anyhow!("Invalid account name: {}", account));
+    }
+}
+
+#[throws]
+fn validate_tag(tag: &str) {
+    if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || matches!(c, ':' | ',')) {
+        throw!(anyhow!("Invalid tag: {}", tag));
+    }
+}
+
+/// Commodities other than plain letters are quoted.
+fn commodity(currency: &str) -> String {
+    if currency.chars().all(|c| c.is_alphabetic()) {
+        currency.to_owned()
+    } else {
+        format!("\"{}\"", currency.replace('"', ""))
+    }
+}
+
+impl Writer for Journal {
+    #[throws]
+    fn write(&self, entry: &Entry) -> String {
+        let payee = one_line(entry.alias.unwrap_or(entry.payee));
+        let narration = one_line(entry.narration);
+        let mut comments = vec![];
+        // The narration is a note after the payee in hledger, or a comment in ledger
+        let description = if narration.is_empty() {
+            payee
+        } else if payee.is_empty() {
+            narration
+        } else if self.hledger {
+            format!("{} | {}", payee, narration)
+        } else {
+            comments.push(narration);
+            payee
+        };
+        let mut output = format!("{} {} {}", entry.date, entry.flag, description);
+        for tag in &entry.tags {
+            validate_tag(tag)?;
+        }
+        if !entry.tags.is_empty() {
+            comments.push(if self.hledger {
+                entry
+                    .tags
+                    .iter()
+                    .map(|tag| format!("{}:", tag))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            } else {
+                format!(":{}:", entry.tags.join(":"))
+            });
+        }
+        for (key, value) in &entry.metadata {
+            validate_tag(key)?;
+            comments.push(format!("{}: {}", key, one_line(value)));
+        }
+        for comment in comments {
+            output.push_str(&format!("\n    ; {}", comment));
+        }
+        let account = entry.posting_account();
+        validate_account(account)?;
+        output.push_str(&format!(
+            "\n    {}  {} {}",
+            account,
+            entry.amount,
+            commodity(entry.currency)
+        ));
+        if let Some((total, currency)) = entry.price {
+            output.push_str(&format!(" @@ {} {}", total, commodity(currency)));
+        }
+        validate_account(entry.fund_account)?;
+        output.push_str(&format!("\n    {}\n", entry.fund_account));
+        output
+    }
+
+    /// An assertion on an empty posting.
+    #[throws]
+    fn write_balance(&self, balance: &Balance, account: &str) -> String {
+        validate_account(account)?;
+        let currency = commodity(&balance.currency);
+        format!(
+            "{} * Balance assertion\n    {}  0 {} = {} {}\n",
+            balance.date.format("%Y-%m-%d"),
+            account,
+            currency,
+            balance.amount,
+            currency
+        )
+    }
+
+    /// Account directives are undated, both ledger-cli and hledger accept accounts used before them.
+    #[throws]
+    fn write_open(&self, _date: &str, account: &str) -> String {
+        validate_account(account)?;
+        format!("account {}\n", account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Journal;
+    use crate::lib::{Balance, Entry, Flow, Writer};
+    use chrono::NaiveDate;
+    use fehler::throws;
+    use rust_decimal::Decimal;
+
+    type Error = anyhow::Error;
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            date: "2020-04-01",
+            flag: "!",
+            payee: "Shop",
+            alias: None,
+            narration: "lunch",
+            tags: vec!["food", "trip-2020"],
+            metadata: vec![("trade_id", "1")],
+            account: Some("Expenses:Food"),
+            amount: Decimal::new(1250, 2),
+            currency: "CNY",
+            price: None,
+            fund_account: "Assets:Wechat",
+            flow: Flow::Expense,
+            source: "wechat",
+            line: 18,
+            raw: "",
+        }
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_ledger() {
+        assert_eq!(
+            Journal { hledger: false }.write(&entry())?,
+            "2020-04-01 ! Shop
+    ; lunch
+    ; :food:trip-2020:
+    ; trade_id: 1
+    Expenses:Food  12.50 CNY
+    Assets:Wechat
+"
+        );
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_hledger() {
+        let entry = Entry {
+            account: None,
+            amount: Decimal::new(-1250, 2),
+            ..entry()
+        };
+        assert_eq!(
+            Journal { hledger: true }.write(&entry)?,
+            "2020-04-01 ! Shop | lunch
+    ; food:, trip-2020:
+    ; trade_id: 1
+    Income:Unknown  -12.50 CNY
+    Assets:Wechat
+"
+        );
+    }
+
+    #[test]
+    fn it_reject_invalid_account() {
+        let entry = Entry {
+            fund_account: "Assets:We  chat",
+            ..entry()
+        };
+        assert!(Journal { hledger: false }.write(&entry).is_err());
+    }
+
+    #[test]
+    #[throws]
+    fn it_write_balance_on_statement_date() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let balance = Balance::closing(date, "1234", Decimal::new(98750, 2), "USD");
+        let journal = Journal { hledger: true };
+        assert_eq!(journal.balance_date(&balance), date);
+        assert_eq!(
+            journal.write_balance(&balance, "Assets:Bank")?,
+            "2024-03-31 * Balance assertion\n    Assets:Bank  0 USD = 987.50 USD\n"
+        );
+    }
+}
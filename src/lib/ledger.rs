@@ -0,0 +1,306 @@
+use super::Rendered;
+use anyhow::Context;
+use fehler::throws;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+type Error = anyhow::Error;
+
+/// Metadata key holding the trade id of the source statement.
+pub static ID_KEY: &str = "trade_id";
+
+/// Metadata key holding the time of the transaction.
+pub static TIME_KEY: &str = "time";
+
+/// Extensions of the ledger files looked for in a directory.
+static LEDGER_EXTENSIONS: &[&str] = &["bean", "beancount", "journal", "ledger", "hledger"];
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+/// Included file of beancount, or ledger-cli and hledger journals whose paths aren't quoted.
+pub fn parse_include(line: &str) -> Option<&str> {
+    line.strip_prefix("include ")
+        .map(str::trim)
+        .map(unquote)
+        .filter(|s| !s.is_empty())
+}
+
+/// Trade id in beancount metadata, or in a journal comment.
+fn parse_id(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    line.strip_prefix(';')
+        .map(str::trim_start)
+        .unwrap_or(line)
+        .strip_prefix(ID_KEY)
+        .and_then(|s| s.strip_prefix(':'))
+        .map(str::trim)
+        .map(unquote)
+        .filter(|s| !s.is_empty())
+}
+
+/// Account opened by a beancount `open` directive or a journal `account` directive, or an account
+/// alone on the line of an accounts file (e.g. the output of `hledger accounts`).
+fn parse_account(line: &str) -> Option<&str> {
+    let (account, bare) = match parse_date(line) {
+        Some(_) => (line[10..].trim_start().strip_prefix("open ")?, false),
+        None => match line.strip_prefix("account ") {
+            Some(account) => (account, false),
+            // Postings are indented
+            None if line.starts_with(char::is_whitespace) => return None,
+            None => (line, true),
+        },
+    };
+    let account = account.trim();
+    let name = account.split(char::is_whitespace).next()?;
+    let is_account =
+        name.contains(':') && name.starts_with(|c: char| c.is_uppercase() || !c.is_ascii());
+    if is_account && (!bare || name.len() == account.len()) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Trade ids and opened accounts recorded in a ledger.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    pub ids: HashSet<String>,
+    pub accounts: HashSet<String>,
+}
+
+impl Ledger {
+    /// Load a beancount or journal file (with the files it includes), or a directory of them.
+    #[throws]
+    pub fn load(path: &Path) -> Self {
+        let mut ledger = Ledger::default();
+        ledger.collect(path, &mut HashSet::new())?;
+        ledger
+    }
+
+    #[throws]
+    fn collect_file(&mut self, path: &Path, visited: &mut HashSet<PathBuf>) {
+        if !visited.insert(path.to_path_buf()) {
+            return;
+        }
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Load ledger {:?} failed", path))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for line in contents.lines() {
+            if let Some(id) = parse_id(line) {
+                self.ids.insert(id.to_owned());
+            } else if let Some(include) = parse_include(line) {
+                self.collect(&dir.join(include), visited)?;
+            } else if let Some(account) = parse_account(line) {
+                self.accounts.insert(account.to_owned());
+            }
+        }
+    }
+
+    #[throws]
+    fn collect(&mut self, path: &Path, visited: &mut HashSet<PathBuf>) {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            for entry in entries {
+                let is_ledger = entry
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| LEDGER_EXTENSIONS.contains(&ext));
+                if entry.is_dir() || is_ledger {
+                    self.collect(&entry, visited)?;
+                }
+            }
+        } else {
+            self.collect_file(path, visited)?;
+        }
+    }
+}
+
+/// Where new entries are inserted into an existing ledger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    /// Before the first directive dated after the entry.
+    Date,
+    End,
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown merge position: {0}")]
+pub struct ParsePositionError(String);
+
+impl FromStr for Position {
+    type Err = ParsePositionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "date" => Ok(Position::Date),
+            "end" => Ok(Position::End),
+            _ => Err(ParsePositionError(s.to_owned())),
+        }
+    }
+}
+
+/// Date of a dated directive, `YYYY-MM-DD` of beancount or `YYYY/MM/DD` of journals.
+fn parse_date(line: &str) -> Option<String> {
+    let date = line.get(..10)?;
+    let valid = date.char_indices().all(|(i, c)| match i {
+        4 | 7 => c == '-' || c == '/',
+        _ => c.is_ascii_digit(),
+    });
+    if valid {
+        Some(date.replace('/', "-"))
+    } else {
+        None
+    }
+}
+
+/// Insert rendered entries into a ledger, keeping its contents byte-for-byte. Entries
+/// whose text is already in the ledger are skipped, returns the merged ledger and how many entries
+/// are inserted.
+pub fn merge(contents: &str, entries: &[Rendered], position: Position) -> (String, usize) {
+    // Offsets to insert before dated directives, with the comments right above them
+    let mut anchors = vec![];
+    let mut offset = 0;
+    let mut comment = None;
+    for line in contents.split_inclusive('\n') {
+        if line.starts_with(';') {
+            comment = comment.or(Some(offset));
+        } else {
+            if let Some(date) = parse_date(line) {
+                anchors.push((date, comment.unwrap_or(offset)));
+            }
+            comment = None;
+        }
+        offset += line.len();
+    }
+    let mut inserts = vec![];
+    for Rendered { date, text, .. } in entries {
+        if contents.contains(text.as_str()) || inserts.iter().any(|(_, _, t)| t == &text) {
+            continue;
+        }
+        let offset = match position {
+            Position::Date => anchors
+                .iter()
+                .find(|(d, _)| d.as_str() > date.as_str())
+                .map_or(contents.len(), |(_, offset)| *offset),
+            Position::End => contents.len(),
+        };
+        inserts.push((offset, date, text));
+    }
+    if position == Position::Date {
+        inserts.sort_by_key(|(offset, date, _)| (*offset, *date));
+    }
+    let mut output = String::new();
+    let mut last = 0;
+    for (offset, _, text) in &inserts {
+        output.push_str(&contents[last..*offset]);
+        last = *offset;
+        if *offset < contents.len() {
+            output.push_str(text);
+            output.push('\n');
+        } else {
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            if !output.is_empty() && !output.ends_with("\n\n") {
+                output.push('\n');
+            }
+            output.push_str(text);
+        }
+    }
+    output.push_str(&contents[last..]);
+    (output, inserts.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, parse_account, parse_id, parse_include, Position};
+    use crate::lib::Rendered;
+
+    #[test]
+    fn it_parse_id() {
+        assert_eq!(parse_id(r#"  trade_id: "4200000001""#), Some("4200000001"));
+        assert_eq!(parse_id("    ; trade_id: 4200000001"), Some("4200000001"));
+        assert_eq!(parse_id(r#"  note: "4200000001""#), None);
+    }
+
+    #[test]
+    fn it_parse_include() {
+        assert_eq!(
+            parse_include(r#"include "2020/03.bean""#),
+            Some("2020/03.bean")
+        );
+        assert_eq!(
+            parse_include("include 2020/03.journal"),
+            Some("2020/03.journal")
+        );
+        assert_eq!(parse_include(r#"2020-03-01 open Assets:Wechat"#), None);
+    }
+
+    fn entries() -> Vec<Rendered> {
+        [
+            "2020-03-02 * \"B\"\n",
+            "2020-03-01 * \"A\"\n",
+            "2020-03-05 * \"C\"\n",
+        ]
+        .iter()
+        .map(|text| Rendered {
+            date: text[..10].to_owned(),
+            fund_account: "Assets:Wechat".to_owned(),
+            text: text.to_string(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn it_merge_in_date_order() {
+        let ledger =
+            "option \"title\" \"Test\"\n\n2020-03-01 * \"A\"\n\n; March 3rd\n2020/03/03 * \"D\"";
+        let (merged, count) = merge(ledger, &entries(), Position::Date);
+        assert_eq!(count, 2);
+        assert_eq!(
+            merged,
+            "option \"title\" \"Test\"\n\n2020-03-01 * \"A\"\n\n2020-03-02 * \"B\"\n\n\
+             ; March 3rd\n2020/03/03 * \"D\"\n\n2020-03-05 * \"C\"\n"
+        );
+    }
+
+    #[test]
+    fn it_merge_at_end() {
+        let (merged, count) = merge("", &entries(), Position::End);
+        assert_eq!(count, 3);
+        assert_eq!(
+            merged,
+            "2020-03-02 * \"B\"\n\n2020-03-01 * \"A\"\n\n2020-03-05 * \"C\"\n"
+        );
+        let (remerged, count) = merge(&merged, &entries(), Position::End);
+        assert_eq!(count, 0);
+        assert_eq!(remerged, merged);
+    }
+
+    #[test]
+    fn it_parse_account() {
+        assert_eq!(
+            parse_account("2020-03-01 open Assets:Wechat CNY"),
+            Some("Assets:Wechat")
+        );
+        assert_eq!(
+            parse_account("account Expenses:Food  ; note"),
+            Some("Expenses:Food")
+        );
+        assert_eq!(parse_account("Expenses:餐饮"), Some("Expenses:餐饮"));
+        assert_eq!(parse_account("2020-03-01 close Assets:Wechat"), None);
+        assert_eq!(parse_account("  Assets:Wechat"), None);
+        assert_eq!(parse_account("Assets:Wechat  25.50 CNY"), None);
+        assert_eq!(parse_account(r#"option "title" "a:b""#), None);
+    }
+}
@@ -1,11 +1,36 @@
+mod beancount;
+mod export;
+mod journal;
+mod ledger;
 mod rules;
+mod split;
 
-use self::rules::Rules;
-use anyhow;
-use fehler::throws;
+use self::beancount::Beancount;
+use self::export::{Csv, Json};
+use self::journal::Journal;
+pub use self::ledger::Position;
+use self::ledger::{Ledger, ID_KEY, TIME_KEY};
+pub use self::rules::Rules;
+pub use self::split::{write_file, Template};
+use anyhow::{anyhow, Context};
+use chrono::NaiveDate;
+use fehler::{throw, throws};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use thiserror::Error;
 
 type Error = anyhow::Error;
 
+/// The regex of the pattern, compiled once into the cell.
+pub fn cached_regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Flow<'a> {
     Income,
@@ -15,9 +40,15 @@ pub enum Flow<'a> {
 
 impl Flow<'_> {
     pub fn is_unknown(&self) -> bool {
+        matches!(self, Flow::Unknown(_))
+    }
+
+    /// Name of the flow in rules and exported data.
+    pub fn name(&self) -> &'static str {
         match self {
-            Flow::Unknown(_) => true,
-            _ => false,
+            Flow::Income => "income",
+            Flow::Expense => "expense",
+            Flow::Unknown(_) => "unknown",
         }
     }
 }
@@ -42,6 +73,12 @@ pub trait Transaction {
     #[throws]
     fn date(&self) -> &str;
 
+    /// Time of the day as `HH:MM:SS`, empty if the statement only has dates.
+    #[throws]
+    fn time(&self) -> &str {
+        ""
+    }
+
     #[throws]
     fn payee(&self) -> &str;
 
@@ -53,6 +90,13 @@ pub trait Transaction {
     #[throws]
     fn narration(&self) -> &str;
 
+    /// Trade id from the source statement, recorded in metadata to skip already imported
+    /// transactions.
+    #[throws]
+    fn id(&self) -> &str {
+        ""
+    }
+
     /// Keys must begin with a lowercase character from a-z and may contain (uppercase or lowercase) letters,
     /// numbers, dashes and underscores.
     #[throws]
@@ -60,110 +104,533 @@ pub trait Transaction {
         vec![]
     }
 
+    /// Amount posted to the payee account, keeping the precision of the source.
     #[throws]
-    fn amount(&self) -> f32;
+    fn amount(&self) -> Decimal;
 
+    /// Commodity of the amount.
     #[throws]
-    fn flow(&self) -> Flow;
+    fn currency(&self) -> &str {
+        "CNY"
+    }
+
+    /// Total price of the amount in the settled commodity, for sources reporting both the original
+    /// and the settled amounts.
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        None
+    }
+
+    #[throws]
+    fn flow(&self) -> Flow<'_>;
 
     fn is_valid(&self) -> bool {
         true
     }
 
+    /// Name of the importer producing the transaction.
+    fn source(&self) -> &str;
+
     fn display(&self) -> String;
 }
 
+/// A transaction with rules applied, ready to be written out.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub date: &'a str,
+    pub flag: &'a str,
+    pub payee: &'a str,
+    pub alias: Option<&'a str>,
+    pub narration: &'a str,
+    pub tags: Vec<&'a str>,
+    pub metadata: Vec<(&'a str, &'a str)>,
+    pub account: Option<&'a str>,
+    pub amount: Decimal,
+    pub currency: &'a str,
+    pub price: Option<(Decimal, &'a str)>,
+    pub fund_account: &'a str,
+    pub flow: Flow<'a>,
+    /// Name of the importer.
+    pub source: &'a str,
+    /// Line and raw text of the statement row.
+    pub line: usize,
+    pub raw: &'a str,
+}
+
+/// Placeholder accounts of entries without a payee account, since a posting without account can't
+/// be parsed in beancount or journal syntax.
+static UNKNOWN_EXPENSE: &str = "Expenses:Unknown";
+static UNKNOWN_INCOME: &str = "Income:Unknown";
+
+impl<'a> Entry<'a> {
+    /// The payee account, or a placeholder by the sign of the amount if none is resolved.
+    pub fn posting_account(&self) -> &'a str {
+        match self.account {
+            Some(account) => account,
+            None if self.amount.is_sign_negative() => UNKNOWN_INCOME,
+            None => UNKNOWN_EXPENSE,
+        }
+    }
+}
+
+/// A balance of a fund from the statement, at the end of the date.
+#[derive(Debug)]
+pub struct Balance {
+    pub date: NaiveDate,
+    pub fund: String,
+    pub amount: Decimal,
+    pub currency: String,
+}
+
+impl Balance {
+    pub fn closing(date: NaiveDate, fund: &str, amount: Decimal, currency: &str) -> Self {
+        Self {
+            date,
+            fund: fund.to_owned(),
+            amount,
+            currency: currency.to_owned(),
+        }
+    }
+}
+
+/// Text of an entry, with the date and fund account it is routed by.
+#[derive(Debug)]
+pub struct Rendered {
+    pub date: String,
+    pub fund_account: String,
+    pub text: String,
+}
+
+/// Renders rule-resolved entries in the syntax of an accounting tool.
+pub trait Writer {
+    /// Written before the entries.
+    fn header(&self) -> String {
+        String::new()
+    }
+
+    #[throws]
+    fn write(&self, entry: &Entry) -> String;
+
+    #[throws]
+    fn write_balance(&self, balance: &Balance, account: &str) -> String;
+
+    /// Date the balance is asserted on, entries are ordered and split by it.
+    fn balance_date(&self, balance: &Balance) -> NaiveDate {
+        balance.date
+    }
+
+    /// Open the account at the date.
+    #[throws]
+    fn write_open(&self, date: &str, account: &str) -> String;
+}
+
+/// Syntax of the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Beancount,
+    Ledger,
+    Hledger,
+    Json,
+    Csv,
+}
+
+impl Format {
+    fn writer(self) -> Box<dyn Writer> {
+        match self {
+            Format::Beancount => Box::new(Beancount),
+            Format::Ledger => Box::new(Journal { hledger: false }),
+            Format::Hledger => Box::new(Journal { hledger: true }),
+            Format::Json => Box::new(Json),
+            Format::Csv => Box::new(Csv),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown output format: {0}")]
+pub struct ParseFormatError(String);
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "beancount" => Ok(Format::Beancount),
+            "ledger" => Ok(Format::Ledger),
+            "hledger" => Ok(Format::Hledger),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(ParseFormatError(s.to_owned())),
+        }
+    }
+}
+
+/// A malformed statement row.
+#[derive(Debug)]
+pub struct Reject {
+    pub line: usize,
+    pub raw: String,
+    pub reason: String,
+}
+
+/// Resolve all the fields to make sure the transaction can be written.
+#[throws]
+fn check(transaction: &dyn Transaction) {
+    beancount::validate_date(transaction.date()?)?;
+    let time = transaction.time()?;
+    if !time.is_empty() {
+        beancount::validate_time(time)?;
+    }
+    transaction.payee()?;
+    transaction.fund()?;
+    transaction.narration()?;
+    transaction.id()?;
+    transaction.metadata()?;
+    transaction.amount()?;
+    transaction.currency()?;
+    transaction.price()?;
+    transaction.flow()?;
+}
+
+/// A transaction with the statement row it is imported from.
+struct Imported {
+    line: usize,
+    raw: String,
+    transaction: Box<dyn Transaction>,
+}
+
+impl Imported {
+    /// Date and time, both are checked when the transaction is added.
+    fn timestamp(&self) -> (&str, &str) {
+        let transaction = self.transaction.as_ref();
+        (
+            transaction.date().unwrap_or_default(),
+            transaction.time().unwrap_or_default(),
+        )
+    }
+}
+
 pub struct Bean<'a> {
-    transactions: Vec<Box<dyn Transaction>>,
+    transactions: Vec<Imported>,
+    rejects: Vec<Reject>,
+    balances: Vec<Balance>,
     default_fund: &'a str,
+    /// Accounts already opened, the others are opened at their first use.
+    opened: Option<HashSet<String>>,
+    warnings: Vec<String>,
 }
 
 impl<'a> Bean<'a> {
     pub fn new(default_fund: &'a str) -> Self {
         Self {
             transactions: Vec::new(),
+            rejects: Vec::new(),
+            balances: Vec::new(),
             default_fund,
+            opened: None,
+            warnings: Vec::new(),
         }
     }
 
+    #[cfg(test)]
     pub fn add(&mut self, transaction: impl Transaction + 'static) {
         if transaction.is_valid() {
-            self.transactions.push(Box::new(transaction));
+            self.transactions.push(Imported {
+                line: 0,
+                raw: String::new(),
+                transaction: Box::new(transaction),
+            });
         } else {
             eprintln!("Ignored invalid transaction: {}", transaction.display());
         }
     }
 
+    /// Add the transaction of a statement row, rejects the row if any field is malformed. Returns
+    /// the transaction if it is added.
+    pub fn add_row(
+        &mut self,
+        line: usize,
+        raw: &str,
+        transaction: impl Transaction + 'static,
+    ) -> Option<&dyn Transaction> {
+        if !transaction.is_valid() {
+            eprintln!("Ignored invalid transaction: {}", transaction.display());
+            None
+        } else if let Err(e) = check(&transaction) {
+            self.reject(Reject {
+                line,
+                raw: raw.to_owned(),
+                reason: format!("{:#}", e),
+            });
+            None
+        } else {
+            self.transactions.push(Imported {
+                line,
+                raw: raw.to_owned(),
+                transaction: Box::new(transaction),
+            });
+            self.transactions.last().map(|t| t.transaction.as_ref())
+        }
+    }
+
+    pub fn reject(&mut self, reject: Reject) {
+        self.rejects.push(reject);
+    }
+
+    /// Warn if a count or total in the statement summary doesn't match the imported transactions.
+    pub fn verify<T: PartialEq + Display>(&mut self, what: &str, reported: T, imported: T) {
+        if reported != imported {
+            self.warnings.push(format!(
+                "{} is {} in the statement, but {} imported",
+                what, reported, imported
+            ));
+        }
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Drop the balance assertions of the statement.
+    pub fn clear_balances(&mut self) {
+        self.balances.clear();
+    }
+
+    pub fn add_balance(&mut self, balance: Balance) {
+        self.balances.push(balance);
+    }
+
+    pub fn rejects(&self) -> &[Reject] {
+        &self.rejects
+    }
+
+    /// Drop the transactions whose trade ids are already in the ledger, returns how many were skipped.
     #[throws]
-    pub fn output_with_rules(&self, rules: Rules) -> String {
-        let mut output = String::new();
-        for transaction in &self.transactions {
-            let payee = transaction.payee()?;
-            let flow = transaction.flow()?;
-            let fund = transaction.fund()?;
-            let mut to_account = rules.get_payee_account(&payee).unwrap_or("").to_owned();
+    pub fn skip_imported(&mut self, ledger: &Path) -> usize {
+        let ids = Ledger::load(ledger)?.ids;
+        let count = self.transactions.len();
+        self.transactions.retain(|t| {
+            t.transaction
+                .id()
+                .map(|id| !ids.contains(id))
+                .unwrap_or(true)
+        });
+        count - self.transactions.len()
+    }
+
+    /// Write open directives for the accounts not opened in the ledger, dated at their first use.
+    #[throws]
+    pub fn open_missing(&mut self, ledger: &Path) {
+        self.opened = Some(Ledger::load(ledger)?.accounts);
+    }
 
-            let flag = if to_account.is_empty() || flow.is_unknown() {
-                "!"
-            } else {
-                "*"
-            };
+    #[throws]
+    fn resolve<'b>(&'b self, imported: &'b Imported, rules: &'b Rules) -> Entry<'b> {
+        let transaction = imported.transaction.as_ref();
+        let flow = transaction.flow()?;
+        let resolved = rules.resolve(transaction)?;
+        let flag = if resolved.account.is_none() || flow.is_unknown() {
+            "!"
+        } else {
+            "*"
+        };
+        let id = transaction.id()?;
+        let mut metadata = transaction.metadata()?;
+        if !id.is_empty() {
+            metadata.insert(0, (ID_KEY, id));
+        }
+        let time = transaction.time()?;
+        if !time.is_empty() {
+            metadata.push((TIME_KEY, time));
+        }
+        metadata.extend(resolved.metadata.iter());
+        Entry {
+            date: transaction.date()?,
+            flag,
+            payee: transaction.payee()?,
+            alias: resolved.alias,
+            narration: transaction.narration()?,
+            tags: resolved.tags,
+            metadata,
+            account: resolved.account,
+            amount: transaction.amount()?,
+            currency: transaction.currency()?,
+            price: transaction.price()?,
+            fund_account: rules
+                .get_fund_account(transaction.fund()?)
+                .unwrap_or(self.default_fund),
+            flow,
+            source: transaction.source(),
+            line: imported.line,
+            raw: &imported.raw,
+        }
+    }
 
-            let mut metadata = transaction
-                .metadata()?
-                .iter()
-                .map(|(k, v)| format!(r#"{}: "{}""#, k, v))
-                .collect::<Vec<_>>()
-                .join("\n");
+    fn iter(&self) -> impl Iterator<Item = &dyn Transaction> {
+        self.transactions.iter().map(|t| t.transaction.as_ref())
+    }
 
-            if !metadata.is_empty() {
-                metadata.insert_str(0, "\n  ");
+    /// The open directives of missing accounts, then the transactions followed by the balances.
+    #[throws]
+    fn render(&self, rules: &Rules, writer: &dyn Writer) -> Vec<Rendered> {
+        let mut rendered = vec![];
+        // Chronological, transactions of the same time keep the order of the statement
+        let mut transactions = self.transactions.iter().collect::<Vec<_>>();
+        transactions.sort_by(|a, b| a.timestamp().cmp(&b.timestamp()));
+        // The first date an account is used, with the fund account of the entry to route its open
+        // directive to the same file
+        let mut used = BTreeMap::new();
+        let mut use_account = |account: &str, date: &str, fund_account: &str| {
+            let first = used
+                .entry(account.to_owned())
+                .or_insert_with(|| (date.to_owned(), fund_account.to_owned()));
+            if date < first.0.as_str() {
+                *first = (date.to_owned(), fund_account.to_owned());
             }
-
-            if !to_account.is_empty() {
-                to_account.push(' ');
+        };
+        for imported in transactions {
+            let entry = self.resolve(imported, rules)?;
+            let text = writer
+                .write(&entry)
+                .with_context(|| format!("Unable to write {}", imported.transaction.display()))?;
+            use_account(entry.posting_account(), entry.date, entry.fund_account);
+            use_account(entry.fund_account, entry.date, entry.fund_account);
+            rendered.push(Rendered {
+                date: entry.date.to_owned(),
+                fund_account: entry.fund_account.to_owned(),
+                text,
+            });
+        }
+        for balance in &self.balances {
+            let account = rules
+                .get_fund_account(&balance.fund)
+                .unwrap_or(self.default_fund);
+            let date = writer.balance_date(balance).format("%Y-%m-%d").to_string();
+            use_account(account, &date, account);
+            rendered.push(Rendered {
+                date,
+                fund_account: account.to_owned(),
+                text: writer.write_balance(balance, account)?,
+            });
+        }
+        if let Some(opened) = &self.opened {
+            let mut missing = used
+                .into_iter()
+                .filter(|(account, _)| !opened.contains(account))
+                .map(|(account, (date, fund_account))| (date, account, fund_account))
+                .collect::<Vec<_>>();
+            missing.sort();
+            let mut opens = vec![];
+            for (date, account, fund_account) in missing {
+                opens.push(Rendered {
+                    text: writer.write_open(&date, &account)?,
+                    date,
+                    fund_account,
+                });
             }
+            rendered.splice(0..0, opens);
+        }
+        rendered
+    }
 
-            output.push_str(
-                format!(
-                    r##"{date} {flag} "{payee}" "{narration}"{metadata}
-  {account}{amount} CNY
-  {fund_account}"##,
-                    date = transaction.date()?,
-                    payee = rules.get_payee_alias(payee).unwrap_or(payee),
-                    narration = transaction.narration()?,
-                    flag = flag,
-                    account = to_account,
-                    amount = transaction.amount()?,
-                    metadata = metadata,
-                    fund_account = rules.get_fund_account(fund).unwrap_or(self.default_fund)
-                )
-                .as_str(),
-            );
-            output.push('\n');
+    #[throws]
+    pub fn output_with_rules(&self, rules: Rules, format: Format) -> String {
+        let writer = format.writer();
+        let mut output = writer.header();
+        for rendered in self.render(&rules, writer.as_ref())? {
+            output.push_str(&rendered.text);
         }
         output
     }
 
     #[throws]
-    pub fn output(&self, edit: bool) -> String {
-        let mut rules = Rules::from_file()?;
+    fn load_rules(&self, rules: &Path, edit: bool) -> Rules {
+        let mut rules = Rules::from_file(rules)?;
         if edit {
-            rules.merge_with_edit(&self.transactions)?;
+            rules.merge_with_edit(self.iter())?;
         } else {
-            rules.merge_and_save(&self.transactions)?;
+            rules.merge_and_save(self.iter())?;
+        }
+        rules
+    }
+
+    #[throws]
+    pub fn output(&self, rules: &Path, edit: bool, format: Format) -> String {
+        self.output_with_rules(self.load_rules(rules, edit)?, format)?
+    }
+
+    /// Insert the entries into the contents of an existing ledger, returns the merged ledger and
+    /// how many entries are inserted.
+    #[throws]
+    pub fn merge_with_rules(
+        &self,
+        contents: &str,
+        rules: Rules,
+        format: Format,
+        position: Position,
+    ) -> (String, usize) {
+        let rendered = self.render_ledger(&rules, format)?;
+        ledger::merge(contents, &rendered, position)
+    }
+
+    #[throws]
+    fn render_ledger(&self, rules: &Rules, format: Format) -> Vec<Rendered> {
+        if matches!(format, Format::Json | Format::Csv) {
+            throw!(anyhow!("Unable to merge {:?} output into a ledger", format));
         }
-        self.output_with_rules(rules)?
+        self.render(rules, format.writer().as_ref())?
+    }
+
+    /// Merge the entries into the files of the template under the directory, returns the files
+    /// with how many entries are inserted.
+    #[throws]
+    pub fn split_with_rules(
+        &self,
+        dir: &Path,
+        template: &Template,
+        rules: Rules,
+        format: Format,
+        position: Position,
+    ) -> Vec<(String, usize)> {
+        let rendered = self.render_ledger(&rules, format)?;
+        split::split(dir, template, rendered, format, position)?
+    }
+
+    #[throws]
+    pub fn merge(
+        &self,
+        contents: &str,
+        rules: &Path,
+        edit: bool,
+        format: Format,
+        position: Position,
+    ) -> (String, usize) {
+        self.merge_with_rules(contents, self.load_rules(rules, edit)?, format, position)?
+    }
+
+    #[throws]
+    pub fn split(
+        &self,
+        dir: &Path,
+        template: &Template,
+        rules: &Path,
+        edit: bool,
+        format: Format,
+        position: Position,
+    ) -> Vec<(String, usize)> {
+        let rules = self.load_rules(rules, edit)?;
+        self.split_with_rules(dir, template, rules, format, position)?
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Bean;
     use super::Rules;
+    use super::{Bean, Flow, Format, Position, Template};
     use crate::test_helpers::MockTransanction;
-    use anyhow;
     use fehler::throws;
+    use rust_decimal::Decimal;
+    use std::{env, fs};
 
     type Error = anyhow::Error;
 
@@ -181,7 +648,7 @@ mod tests {
 "SomeShop" = "Expenses:Custom"
 "#;
         assert_eq!(
-            bean.output_with_rules(Rules::from_str(rules)?)?,
+            bean.output_with_rules(Rules::from_str(rules)?, Format::Beancount)?,
             r#"2020-04-01 ! "SomeShop" "some notes"
   Expenses:Custom 0 CNY
   Assets:Test
@@ -189,11 +656,96 @@ mod tests {
         );
     }
 
+    #[test]
+    #[throws]
+    fn test_sort_by_timestamp() {
+        let mut bean = Bean::new("Assets:Test");
+        let rows = [
+            ("2020-04-02", "08:00:00", "C"),
+            ("2020-04-01", "18:00:00", "B"),
+            ("2020-04-01", "09:30:00", "A1"),
+            ("2020-04-01", "09:30:00", "A2"),
+        ];
+        for (date, time, payee) in &rows {
+            bean.add(MockTransanction {
+                date,
+                time,
+                payee,
+                ..MockTransanction::default()
+            });
+        }
+        let output = bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?;
+        let payees = output
+            .lines()
+            .filter(|line| line.starts_with("2020"))
+            .map(|line| line.split('"').nth(1).unwrap_or(""))
+            .collect::<Vec<_>>();
+        assert_eq!(payees, vec!["A1", "A2", "B", "C"]);
+        assert!(output.contains("  time: \"09:30:00\"\n"));
+    }
+
+    #[test]
+    #[throws]
+    fn test_open_missing_accounts() {
+        let path = env::temp_dir().join("bean-importer-test-open.bean");
+        fs::write(&path, "2020-01-01 open Assets:Test\n")?;
+        let mut bean = Bean::new("Assets:Test");
+        for (date, payee) in &[
+            ("2020-04-02", "Shop"),
+            ("2020-04-01", "Shop"),
+            ("2020-04-03", "Cafe"),
+        ] {
+            bean.add(MockTransanction {
+                date,
+                payee,
+                ..MockTransanction::default()
+            });
+        }
+        bean.open_missing(&path)?;
+        fs::remove_file(path)?;
+        let rules = r#"
+[payee]
+"Shop" = "Expenses:Shop"
+"Cafe" = "Expenses:Cafe"
+"#;
+        let output = bean.output_with_rules(Rules::from_str(rules)?, Format::Beancount)?;
+        assert!(output.starts_with(
+            "2020-04-01 open Expenses:Shop\n2020-04-03 open Expenses:Cafe\n2020-04-01 !"
+        ));
+    }
+
+    #[test]
+    #[throws]
+    fn test_split_open_with_fund_account() {
+        let dir = env::temp_dir().join("bean-importer-test-split-open");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("opened.bean");
+        fs::write(&path, "2020-01-01 open Assets:Test\n")?;
+        let mut bean = Bean::new("Assets:Test");
+        bean.add(MockTransanction {
+            date: "2020-04-01",
+            payee: "Shop",
+            ..MockTransanction::default()
+        });
+        bean.open_missing(&path)?;
+        let rules = Rules::from_str("[payee]\n\"Shop\" = \"Expenses:Shop\"")?;
+        let template = "{account}.bean".parse::<Template>()?;
+        let merged =
+            bean.split_with_rules(&dir, &template, rules, Format::Beancount, Position::End)?;
+        assert_eq!(merged, vec![("Assets/Test.bean".to_owned(), 2)]);
+        let output = fs::read_to_string(dir.join("Assets/Test.bean"))?;
+        assert!(output.starts_with("2020-04-01 open Expenses:Shop\n"));
+        assert!(!dir.join("Expenses").exists());
+        fs::remove_dir_all(dir)?;
+    }
+
     #[test]
     #[throws]
     fn test_output_with_fund() {
         let mut bean = Bean::new("Assets:Test");
         let mut transaction = MockTransanction::default();
+        transaction.date = "2020-04-01";
         transaction.fund = "custom";
         bean.add(transaction);
         let rules = r#"
@@ -201,9 +753,9 @@ mod tests {
 "custom" = "Assets:Custom"
 "#;
         assert_eq!(
-            bean.output_with_rules(Rules::from_str(rules)?)?,
-            r#" ! "" ""
-  0 CNY
+            bean.output_with_rules(Rules::from_str(rules)?, Format::Beancount)?,
+            r#"2020-04-01 ! "" ""
+  Expenses:Unknown 0 CNY
   Assets:Custom
 "#
         );
@@ -214,6 +766,7 @@ mod tests {
     fn test_alias() {
         let mut bean = Bean::new("Assets:Test");
         let mut transaction = MockTransanction::default();
+        transaction.date = "2020-04-01";
         transaction.payee = "test";
         bean.add(transaction);
         let rules = r#"
@@ -221,11 +774,180 @@ mod tests {
 "test" = { alias = "aliased", account = "Expenses:Aliased" }
 "#;
         assert_eq!(
-            bean.output_with_rules(Rules::from_str(rules)?)?,
-            r#" ! "aliased" ""
+            bean.output_with_rules(Rules::from_str(rules)?, Format::Beancount)?,
+            r#"2020-04-01 ! "aliased" ""
   Expenses:Aliased 0 CNY
   Assets:Test
 "#
         );
     }
+
+    #[throws]
+    #[test]
+    fn test_output_exact_amount() {
+        let mut bean = Bean::new("Assets:Test");
+        let transaction = MockTransanction {
+            date: "2020-04-01",
+            amount: "12.30".parse::<Decimal>()?,
+            ..MockTransanction::default()
+        };
+        bean.add(transaction);
+        assert_eq!(
+            bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?,
+            r#"2020-04-01 ! "" ""
+  Expenses:Unknown 12.30 CNY
+  Assets:Test
+"#
+        );
+    }
+
+    #[throws]
+    #[test]
+    fn test_output_with_currency_and_price() {
+        let mut bean = Bean::new("Assets:Test");
+        let transaction = MockTransanction {
+            date: "2020-04-01",
+            amount: "12.00".parse::<Decimal>()?,
+            currency: Some("USD"),
+            price: Some(("86.40".parse::<Decimal>()?, "CNY")),
+            ..MockTransanction::default()
+        };
+        bean.add(transaction);
+        assert_eq!(
+            bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?,
+            r#"2020-04-01 ! "" ""
+  Expenses:Unknown 12.00 USD @@ 86.40 CNY
+  Assets:Test
+"#
+        );
+    }
+
+    #[throws]
+    #[test]
+    fn test_output_with_id_and_metadata() {
+        let mut bean = Bean::new("Assets:Test");
+        let transaction = MockTransanction {
+            date: "2020-04-01",
+            id: "4200000001",
+            meta: vec![("unknown_flow", "已全额退款")],
+            ..MockTransanction::default()
+        };
+        bean.add(transaction);
+        assert_eq!(
+            bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?,
+            r#"2020-04-01 ! "" ""
+  trade_id: "4200000001"
+  unknown_flow: "已全额退款"
+  Expenses:Unknown 0 CNY
+  Assets:Test
+"#
+        );
+    }
+
+    #[throws]
+    #[test]
+    fn test_output_with_tags_and_metadata() {
+        let mut bean = Bean::new("Assets:Test");
+        let transaction = MockTransanction {
+            date: "2020-04-01",
+            payee: "landlord",
+            flow: Flow::Expense,
+            ..MockTransanction::default()
+        };
+        bean.add(transaction);
+        let rules = r#"
+[[match]]
+payee_regex = "^land"
+account = "Expenses:Rent"
+tags = ["home", "monthly"]
+metadata = { category = "rent" }
+"#;
+        assert_eq!(
+            bean.output_with_rules(Rules::from_str(rules)?, Format::Beancount)?,
+            r#"2020-04-01 * "landlord" "" #home #monthly
+  category: "rent"
+  Expenses:Rent 0 CNY
+  Assets:Test
+"#
+        );
+    }
+
+    #[throws]
+    #[test]
+    fn test_output_escaped() {
+        let mut bean = Bean::new("Assets:Test");
+        let transaction = MockTransanction {
+            date: "2020-04-01",
+            payee: r#"Shop "A""#,
+            narration: r"C:\notes",
+            ..MockTransanction::default()
+        };
+        bean.add(transaction);
+        assert_eq!(
+            bean.output_with_rules(Rules::from_str("")?, Format::Beancount)?,
+            r#"2020-04-01 ! "Shop \"A\"" "C:\\notes"
+  Expenses:Unknown 0 CNY
+  Assets:Test
+"#
+        );
+    }
+
+    #[throws]
+    #[test]
+    fn test_refuse_invalid_output() {
+        let mut bean = Bean::new("Assets:Test");
+        bean.add(MockTransanction {
+            date: "2020-04-01",
+            payee: "test",
+            ..MockTransanction::default()
+        });
+        let rules = Rules::from_str("[payee]\ntest = \"expenses:lowercase\"")?;
+        assert!(bean.output_with_rules(rules, Format::Beancount).is_err());
+
+        let mut bean = Bean::new("Assets:Test");
+        bean.add(MockTransanction {
+            date: "2020-04-01",
+            meta: vec![("Invalid Key", "value")],
+            ..MockTransanction::default()
+        });
+        assert!(bean
+            .output_with_rules(Rules::from_str("")?, Format::Beancount)
+            .is_err());
+    }
+
+    #[throws]
+    #[test]
+    fn test_reject_malformed_row() {
+        let mut bean = Bean::new("Assets:Test");
+        bean.add_row(
+            18,
+            "2020/04/01,SomeShop",
+            MockTransanction {
+                date: "2020/04/01",
+                ..MockTransanction::default()
+            },
+        );
+        bean.add_row(
+            19,
+            "2020-04-02,SomeShop",
+            MockTransanction {
+                date: "2020-04-02",
+                ..MockTransanction::default()
+            },
+        );
+        bean.add_row(
+            20,
+            "2020-04-03 9:30,SomeShop",
+            MockTransanction {
+                date: "2020-04-03",
+                time: "9:30",
+                ..MockTransanction::default()
+            },
+        );
+        assert_eq!(bean.transactions.len(), 1);
+        assert_eq!(bean.rejects().len(), 2);
+        assert_eq!(bean.rejects()[0].line, 18);
+        assert_eq!(bean.rejects()[0].reason, "Invalid date: 2020/04/01");
+        assert_eq!(bean.rejects()[1].reason, "Invalid time: 9:30");
+    }
 }
@@ -1,74 +1,310 @@
 use super::Transaction;
 use anyhow::{anyhow, Context};
 use fehler::{throw, throws};
+use regex::Regex;
+use rust_decimal::Decimal;
 use std::env::var;
-use std::fs::OpenOptions;
-use std::io::Read;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use toml_edit::{table, value, Document};
+use toml_edit::{table, value, Document, Item, Table};
 
 static RULES_PATH: &str = "rules.toml";
+static RULES_ENV: &str = "BEAN_IMPORTER_RULES";
+static CONFIG_DIR: &str = "bean-importer";
 
 #[allow(dead_code)]
 type Error = anyhow::Error;
 
+enum Condition {
+    Payee(Regex),
+    Narration(Regex),
+    MinAmount(Decimal),
+    MaxAmount(Decimal),
+    Flow(String),
+    Fund(String),
+    Source(String),
+}
+
+impl Condition {
+    #[throws]
+    fn is_match(&self, transaction: &dyn Transaction) -> bool {
+        match self {
+            Condition::Payee(re) => re.is_match(transaction.payee()?),
+            Condition::Narration(re) => re.is_match(transaction.narration()?),
+            Condition::MinAmount(min) => transaction.amount()?.abs() >= *min,
+            Condition::MaxAmount(max) => transaction.amount()?.abs() < *max,
+            Condition::Flow(flow) => transaction.flow()?.name() == flow,
+            Condition::Fund(fund) => transaction.fund()? == fund,
+            Condition::Source(source) => transaction.source() == source,
+        }
+    }
+}
+
+#[throws]
+fn as_str<'a>(key: &str, item: &'a Item) -> &'a str {
+    item.as_str()
+        .ok_or_else(|| anyhow!("{} should be a string", key))?
+}
+
+#[throws]
+fn as_decimal(key: &str, item: &Item) -> Decimal {
+    let number = match (item.as_integer(), item.as_float(), item.as_str()) {
+        (Some(i), _, _) => i.to_string(),
+        (_, Some(f), _) => f.to_string(),
+        (_, _, Some(s)) => s.to_owned(),
+        _ => throw!(anyhow!("{} should be a number", key)),
+    };
+    number
+        .parse()
+        .with_context(|| format!("Invalid {}: {}", key, number))?
+}
+
+/// A `[[match]]` rule, tried in order for payees without an exact rule in `[payee]`.
+struct Pattern {
+    conditions: Vec<Condition>,
+    account: Option<String>,
+    alias: Option<String>,
+    tags: Vec<String>,
+    metadata: Vec<(String, String)>,
+}
+
+impl Pattern {
+    #[throws]
+    fn from_table(table: &Table) -> Self {
+        let mut pattern = Pattern {
+            conditions: vec![],
+            account: None,
+            alias: None,
+            tags: vec![],
+            metadata: vec![],
+        };
+        for (key, item) in table.iter() {
+            let condition = match key {
+                "payee_regex" => Condition::Payee(Regex::new(as_str(key, item)?)?),
+                "payee_contains" => {
+                    Condition::Payee(Regex::new(&regex::escape(as_str(key, item)?))?)
+                }
+                "narration_regex" => Condition::Narration(Regex::new(as_str(key, item)?)?),
+                "narration_contains" => {
+                    Condition::Narration(Regex::new(&regex::escape(as_str(key, item)?))?)
+                }
+                "min_amount" => Condition::MinAmount(as_decimal(key, item)?),
+                "max_amount" => Condition::MaxAmount(as_decimal(key, item)?),
+                "flow" => match as_str(key, item)? {
+                    flow @ "income" | flow @ "expense" | flow @ "unknown" => {
+                        Condition::Flow(flow.to_owned())
+                    }
+                    flow => throw!(anyhow!(
+                        "Invalid flow {}, should be income, expense or unknown",
+                        flow
+                    )),
+                },
+                "fund" => Condition::Fund(as_str(key, item)?.to_owned()),
+                "source" => Condition::Source(as_str(key, item)?.to_owned()),
+                "account" => {
+                    pattern.account = Some(as_str(key, item)?.to_owned());
+                    continue;
+                }
+                "alias" => {
+                    pattern.alias = Some(as_str(key, item)?.to_owned());
+                    continue;
+                }
+                "tags" => {
+                    let tags = item
+                        .as_array()
+                        .ok_or_else(|| anyhow!("tags should be an array"))?;
+                    for tag in tags.iter() {
+                        let tag = tag
+                            .as_str()
+                            .ok_or_else(|| anyhow!("tags should be strings"))?;
+                        pattern.tags.push(tag.to_owned());
+                    }
+                    continue;
+                }
+                "metadata" => {
+                    let metadata = item
+                        .as_inline_table()
+                        .ok_or_else(|| anyhow!("metadata should be an inline table"))?;
+                    for (k, v) in metadata.iter() {
+                        let v = v
+                            .as_str()
+                            .ok_or_else(|| anyhow!("metadata {} should be a string", k))?;
+                        pattern.metadata.push((k.to_owned(), v.to_owned()));
+                    }
+                    continue;
+                }
+                _ => throw!(anyhow!("Unknown key {}", key)),
+            };
+            pattern.conditions.push(condition);
+        }
+        if pattern.conditions.is_empty() {
+            throw!(anyhow!("No conditions specified"));
+        }
+        if pattern.account.is_none()
+            && pattern.alias.is_none()
+            && pattern.tags.is_empty()
+            && pattern.metadata.is_empty()
+        {
+            throw!(anyhow!(
+                "Nothing to apply, specify account, alias, tags or metadata"
+            ));
+        }
+        pattern
+    }
+
+    #[throws]
+    fn is_match(&self, transaction: &dyn Transaction) -> bool {
+        let mut matched = true;
+        for condition in &self.conditions {
+            if !condition.is_match(transaction)? {
+                matched = false;
+                break;
+            }
+        }
+        matched
+    }
+}
+
+/// Rules resolved for a transaction.
+#[derive(Debug, Default, PartialEq)]
+pub struct Resolved<'a> {
+    pub account: Option<&'a str>,
+    pub alias: Option<&'a str>,
+    pub tags: Vec<&'a str>,
+    pub metadata: Vec<(&'a str, &'a str)>,
+}
+
 pub struct Rules {
     content: Document,
+    patterns: Vec<Pattern>,
+    path: Option<PathBuf>,
     is_dirty: bool,
 }
 
+fn config_dir() -> Option<PathBuf> {
+    var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            var("HOME")
+                .ok()
+                .map(|home| Path::new(&home).join(".config"))
+        })
+        .map(|dir| dir.join(CONFIG_DIR))
+}
+
 impl Rules {
+    /// Locate the rules file, in order of:
+    ///
+    /// 1. the path given explicitly
+    /// 2. `$XDG_CONFIG_HOME/bean-importer/<profile>.toml` if a profile is given
+    /// 3. the `BEAN_IMPORTER_RULES` environment variable
+    /// 4. `rules.toml` in the current directory if it exists
+    /// 5. `$XDG_CONFIG_HOME/bean-importer/rules.toml` if it exists
+    ///
+    /// and falls back to `rules.toml` in the current directory.
     #[throws]
-    pub fn from_file() -> Self {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(RULES_PATH)
-            .context("Load rules failed")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        Self::from_str(&contents)?
+    pub fn locate(path: Option<PathBuf>, profile: Option<&str>) -> PathBuf {
+        let env = var(RULES_ENV).ok().map(PathBuf::from);
+        Self::locate_with(path, profile, env, config_dir())?
+    }
+
+    #[throws]
+    fn locate_with(
+        path: Option<PathBuf>,
+        profile: Option<&str>,
+        env: Option<PathBuf>,
+        config_dir: Option<PathBuf>,
+    ) -> PathBuf {
+        if let Some(path) = path {
+            return path;
+        }
+        if let Some(profile) = profile {
+            let dir = config_dir.ok_or_else(|| anyhow!("Unable to locate the config directory"))?;
+            return dir.join(format!("{}.toml", profile));
+        }
+        if let Some(path) = env {
+            return path;
+        }
+        let local = PathBuf::from(RULES_PATH);
+        match config_dir.map(|dir| dir.join(RULES_PATH)) {
+            Some(config) if !local.exists() && config.exists() => config,
+            _ => local,
+        }
+    }
+
+    /// Load rules from the file, missing file is taken as empty rules.
+    #[throws]
+    pub fn from_file(path: &Path) -> Self {
+        let contents = if path.exists() {
+            fs::read_to_string(path).with_context(|| format!("Load rules {:?} failed", path))?
+        } else {
+            String::new()
+        };
+        let mut rules = Self::from_str(&contents)?;
+        rules.path = Some(path.to_path_buf());
+        rules
     }
 
     #[throws]
     pub fn from_str(s: &str) -> Self {
+        let content: Document = s.parse().context("Invalid rules.toml contents")?;
+        let mut patterns = vec![];
+        if let Some(tables) = content["match"].as_array_of_tables() {
+            for (i, table) in tables.iter().enumerate() {
+                let pattern = Pattern::from_table(table)
+                    .with_context(|| format!("Invalid [[match]] rule #{}", i + 1))?;
+                patterns.push(pattern);
+            }
+        }
         Self {
-            content: s.parse().context("Invalid rules.toml contents")?,
+            content,
+            patterns,
+            path: None,
             is_dirty: false,
         }
     }
 
-    #[throws]
-    fn merge_to_table<F>(&mut self, transactions: &[Box<dyn Transaction>], name: &str, getter: F)
-    where
-        F: Fn(&Box<dyn Transaction>) -> anyhow::Result<&str>,
-    {
+    fn merge_to_table(&mut self, name: &str, keys: Vec<&str>) {
         let root = self.content.as_table_mut();
         let entry = root.entry(name).or_insert(table());
         if let Some(table) = entry.as_table_mut() {
-            for transaction in transactions {
-                let key = getter(transaction)?;
+            for key in keys {
                 if key.is_empty() {
                     continue;
                 }
-                if !table.contains_key(&key) {
+                if !table.contains_key(key) {
                     self.is_dirty = true;
                 }
-                table.entry(&key).or_insert(value(""));
+                table.entry(key).or_insert(value(""));
             }
         }
     }
 
     #[throws]
-    fn merge(&mut self, transactions: &[Box<dyn Transaction>]) {
-        self.merge_to_table(transactions, "fund", |t| t.fund())?;
-        self.merge_to_table(transactions, "payee", |t| t.payee())?;
+    fn merge<'t>(&mut self, transactions: impl IntoIterator<Item = &'t dyn Transaction>) {
+        let mut funds = vec![];
+        let mut payees = vec![];
+        for transaction in transactions {
+            funds.push(transaction.fund()?);
+            // Patterns without an account only add tags or metadata
+            let pattern = self.find_pattern(transaction)?;
+            if pattern.and_then(|p| p.account.as_ref()).is_none() {
+                payees.push(transaction.payee()?);
+            }
+        }
+        self.merge_to_table("fund", funds);
+        self.merge_to_table("payee", payees);
     }
 
     #[throws]
-    pub fn merge_with_edit(&mut self, transactions: &[Box<dyn Transaction>]) {
+    pub fn merge_with_edit<'t>(
+        &mut self,
+        transactions: impl IntoIterator<Item = &'t dyn Transaction>,
+    ) {
         self.merge(transactions)?;
         if self.is_dirty {
             print!("There are new rules should be specified first, save and edit? (yes/no)");
@@ -78,9 +314,9 @@ impl Rules {
             io::stdin().read_line(&mut input)?;
             match input.trim() {
                 "yes" | "y" => {
-                    self.save()?;
-                    Self::open_editor()?;
-                    self.is_dirty = false;
+                    let path = self.save()?;
+                    Self::open_editor(&path)?;
+                    *self = Self::from_file(&path)?;
                 }
                 _ => throw!(anyhow!("Exit")),
             }
@@ -88,22 +324,35 @@ impl Rules {
     }
 
     #[throws]
-    pub fn merge_and_save(&mut self, transactions: &[Box<dyn Transaction>]) {
+    pub fn merge_and_save<'t>(
+        &mut self,
+        transactions: impl IntoIterator<Item = &'t dyn Transaction>,
+    ) {
         self.merge(transactions)?;
-        self.save()?;
+        if self.is_dirty {
+            self.save()?;
+            self.is_dirty = false;
+        }
     }
 
     #[throws]
-    fn save(&self) {
-        let mut rules_file = OpenOptions::new().write(true).open(RULES_PATH)?;
+    fn save(&self) -> PathBuf {
+        let path = self
+            .path
+            .clone()
+            .ok_or_else(|| anyhow!("Rules are not loaded from a file"))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
         let content = self.content.to_string_in_original_order();
-        rules_file.write_all(content.as_bytes())?;
+        fs::write(&path, content).with_context(|| format!("Save rules {:?} failed", path))?;
+        path
     }
 
     #[throws]
-    fn open_editor() {
+    fn open_editor(path: &Path) {
         let editor = var("EDITOR").context("Unable to read $EDITOR")?;
-        process::Command::new(editor).arg(RULES_PATH).status()?;
+        process::Command::new(editor).arg(path).status()?;
     }
 
     fn get_payee(&self, payee: &str, key: &str) -> Option<&str> {
@@ -127,6 +376,46 @@ impl Rules {
         self.get_payee(payee, "alias")
     }
 
+    #[throws]
+    fn find_pattern(&self, transaction: &dyn Transaction) -> Option<&Pattern> {
+        let mut found = None;
+        for pattern in &self.patterns {
+            if pattern.is_match(transaction)? {
+                found = Some(pattern);
+                break;
+            }
+        }
+        found
+    }
+
+    /// Resolve the payee rule of a transaction, the account and alias of exact `[payee]` rules
+    /// take precedence over `[[match]]` rules, whose tags and metadata are kept.
+    #[throws]
+    pub fn resolve(&self, transaction: &dyn Transaction) -> Resolved<'_> {
+        let payee = transaction.payee()?;
+        let account = self.get_payee_account(payee).filter(|s| !s.is_empty());
+        match (account, self.find_pattern(transaction)?) {
+            (account, Some(pattern)) => Resolved {
+                account: account.or(pattern.account.as_deref()),
+                alias: match account {
+                    Some(_) => self.get_payee_alias(payee),
+                    None => pattern.alias.as_deref(),
+                },
+                tags: pattern.tags.iter().map(String::as_str).collect(),
+                metadata: pattern
+                    .metadata
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect(),
+            },
+            (account, None) => Resolved {
+                account,
+                alias: self.get_payee_alias(payee),
+                ..Resolved::default()
+            },
+        }
+    }
+
     pub fn get_fund_account(&self, fund: &str) -> Option<&str> {
         self.content["fund"]
             .as_table()
@@ -137,29 +426,60 @@ impl Rules {
 
 #[cfg(test)]
 mod tests {
-    use super::Rules;
+    use super::{Resolved, Rules};
+    use crate::lib::Flow;
     use crate::test_helpers::MockTransanction;
-    use anyhow;
     use fehler::throws;
+    use std::path::PathBuf;
+    use std::{env, fs};
 
     type Error = anyhow::Error;
 
     #[throws]
     #[test]
     fn test_from_file() {
-        let rules = Rules::from_file()?;
+        let path = env::temp_dir().join("bean-importer-test-from-file.toml");
+        let mut rules = Rules::from_file(&path)?;
         assert_eq!(rules.is_dirty, false);
+        assert!(!path.exists());
+        rules.merge_and_save(vec![])?;
+        assert!(!path.exists());
+        let transaction = MockTransanction::gen_with_payee("starbuck");
+        rules.merge_and_save(vec![transaction.as_ref()])?;
+        assert!(Rules::from_file(&path)?
+            .get_payee_account("starbuck")
+            .is_some());
+        fs::remove_file(path)?;
+    }
+
+    #[throws]
+    #[test]
+    fn test_locate() {
+        let env = Some(PathBuf::from("env/rules.toml"));
+        let config = Some(PathBuf::from("config/bean-importer"));
+        let path = Rules::locate_with(
+            Some(PathBuf::from("ledger/rules.toml")),
+            Some("home"),
+            env.clone(),
+            config.clone(),
+        )?;
+        assert_eq!(path, PathBuf::from("ledger/rules.toml"));
+        let path = Rules::locate_with(None, Some("home"), env.clone(), config.clone())?;
+        assert_eq!(path, PathBuf::from("config/bean-importer/home.toml"));
+        assert!(Rules::locate_with(None, Some("home"), env.clone(), None).is_err());
+        let path = Rules::locate_with(None, None, env, config)?;
+        assert_eq!(path, PathBuf::from("env/rules.toml"));
     }
 
     #[throws]
     #[test]
     fn test_merge_to_empty() {
         let mut rules = Rules::from_str("")?;
-        let transactions = vec![
+        let transactions = [
             MockTransanction::gen_with_payee("starbuck"),
             MockTransanction::gen_with_fund("wechat"),
         ];
-        rules.merge(&transactions)?;
+        rules.merge(transactions.iter().map(|t| t.as_ref()))?;
         assert_eq!(rules.is_dirty, true);
         assert_eq!(
             rules.content.to_string(),
@@ -182,11 +502,11 @@ starbuck = ""
 test = "existed"
 "#,
         )?;
-        let transactions = vec![
+        let transactions = [
             MockTransanction::gen_with_payee("test"),
             MockTransanction::gen_with_payee("newone"),
         ];
-        rules.merge(&transactions)?;
+        rules.merge(transactions.iter().map(|t| t.as_ref()))?;
         assert_eq!(
             rules.content.to_string(),
             r#"
@@ -236,4 +556,189 @@ wechat = "Assets:Wechat"
         assert_eq!(rules.get_fund_account("wechat"), Some("Assets:Wechat"));
         assert_eq!(rules.get_fund_account("hole"), None);
     }
+
+    #[throws]
+    #[test]
+    fn test_resolve_with_patterns() {
+        let rules = Rules::from_str(
+            r#"
+[payee]
+"美团外卖-总店" = "Expenses:Exact"
+
+[[match]]
+payee_regex = "^美团外卖-"
+alias = "美团外卖"
+account = "Expenses:Food"
+tags = ["food"]
+metadata = { category = "takeout" }
+
+[[match]]
+narration_contains = "话费"
+account = "Expenses:Phone"
+"#,
+        )?;
+        let exact = MockTransanction {
+            payee: "美团外卖-总店",
+            ..MockTransanction::default()
+        };
+        assert_eq!(
+            rules.resolve(&exact)?,
+            Resolved {
+                account: Some("Expenses:Exact"),
+                alias: None,
+                tags: vec!["food"],
+                metadata: vec![("category", "takeout")],
+            }
+        );
+        let matched = MockTransanction {
+            payee: "美团外卖-某某店",
+            narration: "话费充值",
+            ..MockTransanction::default()
+        };
+        assert_eq!(
+            rules.resolve(&matched)?,
+            Resolved {
+                account: Some("Expenses:Food"),
+                alias: Some("美团外卖"),
+                tags: vec!["food"],
+                metadata: vec![("category", "takeout")],
+            }
+        );
+        let matched = MockTransanction {
+            payee: "中国移动",
+            narration: "话费充值",
+            ..MockTransanction::default()
+        };
+        assert_eq!(rules.resolve(&matched)?.account, Some("Expenses:Phone"));
+        assert_eq!(
+            rules.resolve(&MockTransanction::default())?,
+            Resolved::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let err = Rules::from_str(
+            r#"
+[[match]]
+payee_regex = "("
+account = "Expenses:Food"
+"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Invalid [[match]] rule #1");
+        assert!(Rules::from_str("[[match]]\naccount = \"Expenses:Food\"").is_err());
+        assert!(Rules::from_str("[[match]]\npayee = \"x\"\naccount = \"Expenses:Food\"").is_err());
+    }
+
+    #[throws]
+    #[test]
+    fn test_merge_skip_matched_payee() {
+        let mut rules = Rules::from_str(
+            r#"
+[[match]]
+payee_contains = "美团外卖"
+account = "Expenses:Food"
+"#,
+        )?;
+        let transactions = [MockTransanction::gen_with_payee("美团外卖-某某店")];
+        rules.merge(transactions.iter().map(|t| t.as_ref()))?;
+        assert!(!rules.is_dirty);
+    }
+
+    #[throws]
+    #[test]
+    fn test_merge_payee_matched_without_account() {
+        let mut rules = Rules::from_str(
+            r#"
+[[match]]
+payee_contains = "美团外卖"
+tags = ["food"]
+"#,
+        )?;
+        let transactions = [MockTransanction::gen_with_payee("美团外卖-某某店")];
+        rules.merge(transactions.iter().map(|t| t.as_ref()))?;
+        assert!(rules.is_dirty);
+        assert_eq!(rules.get_payee_account("美团外卖-某某店"), Some(""));
+    }
+
+    #[throws]
+    #[test]
+    fn test_resolve_with_conditions() {
+        let rules = Rules::from_str(
+            r#"
+[[match]]
+payee_contains = "支付宝"
+max_amount = 100
+flow = "expense"
+account = "Expenses:Groceries"
+
+[[match]]
+payee_contains = "支付宝"
+min_amount = "3000.00"
+source = "alipay"
+account = "Expenses:Rent"
+tags = ["home"]
+metadata = { category = "rent" }
+
+[[match]]
+narration_contains = "红包"
+flow = "income"
+fund = "零钱"
+account = "Income:RedPacket"
+"#,
+        )?;
+        let groceries = MockTransanction {
+            payee: "支付宝-超市",
+            amount: "99.99".parse()?,
+            flow: Flow::Expense,
+            ..MockTransanction::default()
+        };
+        assert_eq!(
+            rules.resolve(&groceries)?.account,
+            Some("Expenses:Groceries")
+        );
+        let rent = MockTransanction {
+            payee: "支付宝-房东",
+            amount: "3000".parse()?,
+            flow: Flow::Expense,
+            source: "alipay",
+            ..MockTransanction::default()
+        };
+        assert_eq!(
+            rules.resolve(&rent)?,
+            Resolved {
+                account: Some("Expenses:Rent"),
+                tags: vec!["home"],
+                metadata: vec![("category", "rent")],
+                ..Resolved::default()
+            }
+        );
+        let red_packet = MockTransanction {
+            narration: "微信红包",
+            amount: "-10".parse()?,
+            flow: Flow::Income,
+            fund: "零钱",
+            ..MockTransanction::default()
+        };
+        assert_eq!(
+            rules.resolve(&red_packet)?.account,
+            Some("Income:RedPacket")
+        );
+        let outgoing = MockTransanction {
+            flow: Flow::Expense,
+            ..red_packet
+        };
+        assert_eq!(rules.resolve(&outgoing)?.account, None);
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        assert!(Rules::from_str("[[match]]\nflow = \"out\"\naccount = \"Expenses:A\"").is_err());
+        assert!(
+            Rules::from_str("[[match]]\nmin_amount = \"x\"\naccount = \"Expenses:A\"").is_err()
+        );
+        assert!(Rules::from_str("[[match]]\nsource = \"alipay\"").is_err());
+    }
 }
@@ -0,0 +1,204 @@
+use super::ledger::{self, parse_include, Position};
+use super::{Format, Rendered};
+use anyhow::{anyhow, Context};
+use fehler::{throw, throws};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+type Error = anyhow::Error;
+
+static PLACEHOLDERS: &[&str] = &["year", "month", "day", "account"];
+
+/// Relative path of the file an entry is routed to, e.g. `{year}/{month}.bean`. `{year}`, `{month}`
+/// and `{day}` are parts of the date, and `{account}` is the fund account with `:` as directory
+/// separators.
+#[derive(Debug)]
+pub struct Template(String);
+
+impl FromStr for Template {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(s: &str) -> Self {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in template: {}", s))?;
+            let name = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&name) {
+                throw!(anyhow!(
+                    "Unknown placeholder {{{}}} in template: {}",
+                    name,
+                    s
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if s.is_empty() || Path::new(s).is_absolute() || s.split('/').any(|part| part == "..") {
+            throw!(anyhow!("Template must be a relative path: {}", s));
+        }
+        Template(s.to_owned())
+    }
+}
+
+impl Template {
+    fn path(&self, rendered: &Rendered) -> String {
+        let date = &rendered.date;
+        self.0
+            .replace("{year}", date.get(..4).unwrap_or(""))
+            .replace("{month}", date.get(5..7).unwrap_or(""))
+            .replace("{day}", date.get(8..10).unwrap_or(""))
+            .replace("{account}", &rendered.fund_account.replace(':', "/"))
+    }
+
+    /// The index is next to the generated files at the top of the directory, with the extension
+    /// of the template.
+    fn index(&self) -> String {
+        let extension = Path::new(&self.0)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("bean");
+        format!("index.{}", extension)
+    }
+}
+
+/// Beancount quotes included paths while ledger-cli and hledger don't.
+fn include(format: Format, path: &str) -> String {
+    match format {
+        Format::Beancount => format!("include \"{}\"\n", path),
+        _ => format!("include {}\n", path),
+    }
+}
+
+/// Replace the file only after the new contents are written.
+#[throws]
+pub fn write_file(path: &Path, contents: &str) {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)?;
+}
+
+#[throws]
+fn read_file(path: &Path) -> String {
+    if path.exists() {
+        fs::read_to_string(path).with_context(|| format!("Load ledger {:?} failed", path))?
+    } else {
+        String::new()
+    }
+}
+
+/// Append the includes missing in the index, existing contents are kept.
+fn update_index(contents: &str, paths: &[String], format: Format) -> String {
+    let mut output = contents.to_owned();
+    for path in paths {
+        if contents
+            .lines()
+            .any(|line| parse_include(line) == Some(path))
+        {
+            continue;
+        }
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output.push_str(&include(format, path));
+    }
+    output
+}
+
+/// Merge the entries into the files of the template under the directory, and include the files in
+/// the index. Returns the relative paths of the files with how many entries are inserted.
+#[throws]
+pub fn split(
+    dir: &Path,
+    template: &Template,
+    entries: Vec<Rendered>,
+    format: Format,
+    position: Position,
+) -> Vec<(String, usize)> {
+    let mut files = BTreeMap::new();
+    for rendered in entries {
+        files
+            .entry(template.path(&rendered))
+            .or_insert_with(Vec::new)
+            .push(rendered);
+    }
+    let mut merged = vec![];
+    for (path, entries) in files {
+        let file = dir.join(&path);
+        let contents = read_file(&file)?;
+        let (output, count) = ledger::merge(&contents, &entries, position);
+        if count > 0 {
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_file(&file, &output)?;
+        }
+        merged.push((path, count));
+    }
+    let index = dir.join(template.index());
+    let contents = read_file(&index)?;
+    let paths = merged
+        .iter()
+        .filter(|(path, _)| dir.join(path).exists())
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    let output = update_index(&contents, &paths, format);
+    if output != contents {
+        fs::create_dir_all(dir)?;
+        write_file(&index, &output)?;
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_index, Template};
+    use crate::lib::{Format, Rendered};
+    use fehler::throws;
+    use std::str::FromStr;
+
+    type Error = anyhow::Error;
+
+    #[test]
+    #[throws]
+    fn it_expand_template() {
+        let rendered = Rendered {
+            date: "2024-03-05".to_owned(),
+            fund_account: "Assets:Bank:Checking".to_owned(),
+            text: String::new(),
+        };
+        let template = Template::from_str("{year}/{month}.bean")?;
+        assert_eq!(template.path(&rendered), "2024/03.bean");
+        assert_eq!(template.index(), "index.bean");
+        let template = Template::from_str("{account}/{year}{month}{day}.journal")?;
+        assert_eq!(
+            template.path(&rendered),
+            "Assets/Bank/Checking/20240305.journal"
+        );
+        assert_eq!(template.index(), "index.journal");
+        assert!(Template::from_str("{year}/{week}.bean").is_err());
+        assert!(Template::from_str("{year}/{month.bean").is_err());
+        assert!(Template::from_str("../{year}.bean").is_err());
+    }
+
+    #[test]
+    fn it_update_index() {
+        let paths = vec!["2024/02.bean".to_owned(), "2024/03.bean".to_owned()];
+        assert_eq!(
+            update_index(
+                "option \"title\" \"Test\"\ninclude \"2024/02.bean\"",
+                &paths,
+                Format::Beancount
+            ),
+            "option \"title\" \"Test\"\ninclude \"2024/02.bean\"\ninclude \"2024/03.bean\"\n"
+        );
+        assert_eq!(
+            update_index("", &paths[1..], Format::Ledger),
+            "include 2024/03.bean\n"
+        );
+    }
+}
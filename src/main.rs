@@ -3,18 +3,64 @@ mod lib;
 #[cfg(test)]
 mod test_helpers;
 
-use crate::importers::{alipay, wechat};
-use std::fs::File;
+use crate::importers::generic::{self, Profile};
+use crate::importers::{alipay, alipay2, camt053, cmb, mt940, ofx, wechat};
+use crate::lib::{write_file, Format, Position, Reject, Rules, Template};
+use anyhow::anyhow;
+use encoding_rs::Encoding;
+use fehler::{throw, throws};
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 use structopt::StructOpt;
 use thiserror::Error;
 
-#[derive(Debug)]
+type Error = anyhow::Error;
+
+#[derive(Debug, PartialEq)]
 enum Source {
+    Auto,
     Wechat,
     Alipay,
+    Alipay2,
+    Cmb,
+    Generic,
+    Ofx,
+    Camt053,
+    Mt940,
+}
+
+impl Source {
+    const KNOWN: &'static [&'static str] = &[
+        "wechat", "alipay", "alipay2", "cmb", "ofx", "camt053", "mt940",
+    ];
+
+    /// Detect the source by the banner and header row of the statement.
+    #[throws]
+    fn detect(contents: &str) -> Self {
+        if ofx::is_match(contents) {
+            Source::Ofx
+        } else if camt053::is_match(contents) {
+            Source::Camt053
+        } else if mt940::is_match(contents) {
+            Source::Mt940
+        } else if wechat::is_match(contents) {
+            Source::Wechat
+        } else if alipay::is_match(contents) {
+            Source::Alipay
+        } else if alipay2::is_match(contents) {
+            Source::Alipay2
+        } else if cmb::is_match(contents) {
+            Source::Cmb
+        } else {
+            throw!(anyhow!(
+                "Unable to detect the source of input, known sources: {}",
+                Self::KNOWN.join(", ")
+            ))
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -26,18 +72,42 @@ impl FromStr for Source {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "auto" => Ok(Source::Auto),
             "wechat" => Ok(Source::Wechat),
             "alipay" => Ok(Source::Alipay),
+            "alipay2" => Ok(Source::Alipay2),
+            "cmb" => Ok(Source::Cmb),
+            "generic" => Ok(Source::Generic),
+            "ofx" => Ok(Source::Ofx),
+            "camt053" => Ok(Source::Camt053),
+            "mt940" => Ok(Source::Mt940),
             _ => Err(ParseSourceError(s.to_owned())),
         }
     }
 }
 
+#[throws]
+fn parse_encoding(label: &str) -> &'static Encoding {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("Unknown encoding: {}", label))?
+}
+
+#[throws]
+fn write_rejects(path: &Path, rejects: &[Reject]) {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["line", "reason", "raw"])?;
+    for reject in rejects {
+        writer.write_record([&reject.line.to_string(), &reject.reason, &reject.raw])?;
+    }
+    writer.flush()?;
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bean-import", about = "Beancount importer")]
 struct Opt {
     /// Activate debug mode
     // short and long flags (-d, --debug) will be deduced from the field's name
+    // The flag is accepted but not read yet
+    #[allow(dead_code)]
     #[structopt(short, long)]
     debug: bool,
 
@@ -46,9 +116,55 @@ struct Opt {
     #[structopt(short, long)]
     edit: bool,
 
-    /// Set source(wechat or alipay)
-    #[structopt(short = "s", long = "source", default_value = "wechat")]
+    /// Set source(auto, wechat, alipay, alipay2, cmb, ofx, camt053, mt940 or generic)
+    #[structopt(short = "s", long = "source", default_value = "auto")]
     source: Source,
+
+    /// Profile describing the CSV layout for the generic source, implies `--source generic`
+    #[structopt(long, parse(from_os_str))]
+    csv_profile: Option<PathBuf>,
+
+    /// Output format(beancount, ledger, hledger, json or csv)
+    #[structopt(short, long, default_value = "beancount")]
+    format: Format,
+
+    /// Set input encoding(e.g. gbk, gb18030 or utf-16le), detected if not present
+    #[structopt(long, parse(try_from_str = parse_encoding))]
+    encoding: Option<&'static Encoding>,
+
+    /// Existing ledger file or directory, transactions already imported into it are skipped
+    #[structopt(short, long, parse(from_os_str))]
+    ledger: Option<PathBuf>,
+
+    /// Ledger or accounts file (or directory), open directives are written for the accounts not
+    /// opened in it
+    #[structopt(long, parse(from_os_str))]
+    open: Option<PathBuf>,
+
+    /// Rules file, see README for how it is located if not present
+    #[structopt(short, long, parse(from_os_str))]
+    rules: Option<PathBuf>,
+
+    /// Use rules of the profile from `$XDG_CONFIG_HOME/bean-importer/<profile>.toml`
+    #[structopt(short, long)]
+    profile: Option<String>,
+
+    /// Password of the zip archive, prompted for if not present
+    #[structopt(long)]
+    password: Option<String>,
+
+    /// Abort if any row is rejected instead of skipping the malformed rows
+    #[structopt(long)]
+    strict: bool,
+
+    /// Leave out the balance assertions of the statement closing balances
+    #[structopt(long)]
+    no_balance: bool,
+
+    /// Write malformed rows to the file
+    #[structopt(long, parse(from_os_str))]
+    rejects: Option<PathBuf>,
+
     /// Input file
     #[structopt(parse(from_os_str))]
     input: PathBuf,
@@ -56,21 +172,180 @@ struct Opt {
     /// Output file, stdout if not present
     #[structopt(parse(from_os_str))]
     output: Option<PathBuf>,
+
+    /// Merge into the existing output file instead of overwriting it, inserting new transactions
+    /// in date order (date) or at the end (end)
+    #[structopt(short, long)]
+    merge: Option<Position>,
+
+    /// Split the output into files of the template under the output directory, e.g.
+    /// `{year}/{month}.bean` or `{account}.bean`, merged at the end unless `--merge` is present
+    #[structopt(long)]
+    split: Option<Template>,
 }
 
 fn main() -> anyhow::Result<()> {
     let opt = Opt::from_args();
-    let bean = match opt.source {
-        Source::Alipay => alipay::import(opt.input, opt.edit)?,
-        Source::Wechat => wechat::import(opt.input, opt.edit)?,
+    if opt.merge.is_some() && opt.output.is_none() {
+        throw!(anyhow!("--merge requires an output file"));
+    }
+    if opt.split.is_some() && opt.output.is_none() {
+        throw!(anyhow!("--split requires an output directory"));
+    }
+    let profile = match &opt.csv_profile {
+        Some(path) => Some(Rc::new(Profile::from_file(path)?)),
+        None => None,
     };
-    match opt.output {
-        Some(path) => {
+    let encoding = opt
+        .encoding
+        .or_else(|| profile.as_ref().and_then(|p| p.encoding));
+    let contents = importers::read(&opt.input, encoding, opt.password.as_deref())?;
+    let source = match opt.source {
+        Source::Auto if profile.is_some() => Source::Generic,
+        Source::Auto => Source::detect(&contents)?,
+        source => source,
+    };
+    let mut bean = match source {
+        Source::Alipay => alipay::import(&contents)?,
+        Source::Alipay2 => alipay2::import(&contents)?,
+        Source::Cmb => cmb::import(&contents)?,
+        Source::Ofx => ofx::import(&contents)?,
+        Source::Camt053 => camt053::import(&contents)?,
+        Source::Mt940 => mt940::import(&contents)?,
+        Source::Generic => match &profile {
+            Some(profile) => generic::import(&contents, profile)?,
+            None => throw!(anyhow!("The generic source requires --csv-profile")),
+        },
+        Source::Wechat => wechat::import(&contents)?,
+        Source::Auto => unreachable!(),
+    };
+    if let (true, Some(reject)) = (opt.strict, bean.rejects().first()) {
+        throw!(anyhow!(
+            "Malformed row at line {}: {}\n{}",
+            reject.line,
+            reject.reason,
+            reject.raw
+        ));
+    }
+    if let Some(ledger) = &opt.ledger {
+        let skipped = bean.skip_imported(ledger)?;
+        eprintln!("Skipped {} already imported transactions", skipped);
+    }
+    if opt.no_balance {
+        bean.clear_balances();
+    }
+    for warning in bean.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(ledger) = &opt.open {
+        bean.open_missing(ledger)?;
+    }
+    let rules = Rules::locate(opt.rules, opt.profile.as_deref())?;
+    match (opt.output, opt.merge, opt.split) {
+        (Some(dir), position, Some(template)) => {
+            if dir.exists() {
+                let skipped = bean.skip_imported(&dir)?;
+                eprintln!("Skipped {} transactions already in {:?}", skipped, dir);
+            }
+            let position = position.unwrap_or(Position::End);
+            let files = bean.split(&dir, &template, &rules, opt.edit, opt.format, position)?;
+            for (path, count) in files {
+                println!("Merged {} entries into {:?}", count, dir.join(path));
+            }
+        }
+        (Some(path), Some(position), None) => {
+            let contents = if path.exists() {
+                let skipped = bean.skip_imported(&path)?;
+                eprintln!("Skipped {} transactions already in {:?}", skipped, path);
+                fs::read_to_string(&path)?
+            } else {
+                String::new()
+            };
+            let (merged, count) = bean.merge(&contents, &rules, opt.edit, opt.format, position)?;
+            write_file(&path, &merged)?;
+            println!("Merged {} entries into {:?}", count, path);
+        }
+        (Some(path), None, None) => {
+            let output = bean.output(&rules, opt.edit, opt.format)?;
             let mut file = File::create(path)?;
-            file.write_all(bean.as_bytes())?;
+            file.write_all(output.as_bytes())?;
             println!("Import success!");
         }
-        None => println!("{}", bean),
+        (None, _, _) => print!("{}", bean.output(&rules, opt.edit, opt.format)?),
+    }
+    let rejects = bean.rejects();
+    if !rejects.is_empty() {
+        eprintln!("Rejected {} malformed rows:", rejects.len());
+        for reject in rejects {
+            eprintln!("  line {}: {}", reject.line, reject.reason);
+        }
+    }
+    if let Some(path) = &opt.rejects {
+        write_rejects(path, rejects)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_detect_sources_in_order() {
+        let samples = [
+            (
+                "OFXHEADER:100\n<OFX>\n<BANKMSGSRSV1>\n<STMTTRNRS>",
+                Source::Ofx,
+            ),
+            (
+                "<?xml version=\"1.0\"?>\n<Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:camt.053.001.02\">\n<BkToCstmrStmt>",
+                Source::Camt053,
+            ),
+            (
+                ":20:STARTUMS\n:25:12345678/0001\n:28C:1/1\n:60F:C200101EUR100,00\n:61:2001020102D10,00NTRFNONREF\n:86:Coffee",
+                Source::Mt940,
+            ),
+            (
+                "交易时间,交易类型,交易对方,商品,收/支,金额(元),支付方式,当前状态,交易单号,商户单号,备注\n2020-01-01 12:00:00,商户消费,Coffee,Latte,支出,¥10.00,零钱,支付成功,1,2,/",
+                Source::Wechat,
+            ),
+            (
+                "交易号,商户订单号,交易创建时间,付款时间,最近修改时间,交易来源地,类型,交易对方,商品名称,金额（元）,收/支,交易状态,服务费（元）,成功退款（元）,备注,资金状态",
+                Source::Alipay,
+            ),
+            (
+                "交易时间,交易分类,交易对方,对方账号,商品说明,收/支,金额,收/付款方式,交易状态,交易订单号,商家订单号,备注",
+                Source::Alipay2,
+            ),
+            (
+                "交易日,记账日,交易摘要,人民币金额,卡号末四位,交易地金额\n2020-01-01,2020-01-02,Coffee,10.00,1234,10.00",
+                Source::Cmb,
+            ),
+        ];
+        for (contents, source) in samples.iter() {
+            assert_eq!(&Source::detect(contents).unwrap(), source);
+        }
+        // Banners win over the header rows that follow them.
+        assert_eq!(
+            Source::detect("微信支付账单明细\n交易时间,交易类型").unwrap(),
+            Source::Wechat
+        );
+        assert_eq!(
+            Source::detect("支付宝交易记录明细查询\n交易时间,交易分类").unwrap(),
+            Source::Alipay
+        );
+        // An OFX statement is not mistaken for a CSV export it mentions.
+        assert_eq!(
+            Source::detect("<OFX>\n<MEMO>招商银行信用卡").unwrap(),
+            Source::Ofx
+        );
+    }
+
+    #[test]
+    fn it_reject_unknown_sources() {
+        for contents in ["", "date,payee,amount\n2020-01-01,Coffee,10.00"].iter() {
+            let err = Source::detect(contents).unwrap_err();
+            assert!(err.to_string().contains("known sources: wechat, alipay"));
+        }
+    }
+}
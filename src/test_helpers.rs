@@ -1,28 +1,41 @@
+use crate::importers::csv::Record;
 use crate::lib::{Flow, Transaction};
 use csv::{ReaderBuilder, StringRecord, Trim};
 use fehler::throws;
+use rust_decimal::Decimal;
+use std::rc::Rc;
 
 type Error = anyhow::Error;
 
 #[throws]
-pub fn gen_record(s: &str) -> StringRecord {
+pub fn gen_record(headers: &[&str], s: &str) -> Record {
     let mut record = StringRecord::new();
     ReaderBuilder::new()
         .trim(Trim::All)
         .has_headers(false)
         .from_reader(s.as_bytes())
         .read_record(&mut record)?;
-    record
+    let headers = headers
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.to_string(), i))
+        .collect();
+    Record::new(Rc::new(headers), record)
 }
 
 #[derive(Default)]
 pub struct MockTransanction<'a> {
     pub date: &'a str,
+    pub time: &'a str,
     pub payee: &'a str,
     pub fund: &'a str,
     pub narration: &'a str,
+    pub id: &'a str,
     pub flow: Flow<'a>,
-    pub amount: f32,
+    pub source: &'a str,
+    pub amount: Decimal,
+    pub currency: Option<&'a str>,
+    pub price: Option<(Decimal, &'a str)>,
     pub meta: Vec<(&'a str, &'a str)>,
 }
 
@@ -47,6 +60,10 @@ impl Transaction for MockTransanction<'_> {
         self.date
     }
     #[throws]
+    fn time(&self) -> &str {
+        self.time
+    }
+    #[throws]
     fn payee(&self) -> &str {
         self.payee
     }
@@ -59,17 +76,32 @@ impl Transaction for MockTransanction<'_> {
         self.narration
     }
     #[throws]
-    fn flow(&self) -> Flow {
+    fn id(&self) -> &str {
+        self.id
+    }
+    #[throws]
+    fn flow(&self) -> Flow<'_> {
         self.flow.clone()
     }
     #[throws]
-    fn amount(&self) -> f32 {
+    fn amount(&self) -> Decimal {
         self.amount
     }
     #[throws]
+    fn currency(&self) -> &str {
+        self.currency.unwrap_or("CNY")
+    }
+    #[throws]
+    fn price(&self) -> Option<(Decimal, &str)> {
+        self.price
+    }
+    #[throws]
     fn metadata(&self) -> Vec<(&str, &str)> {
         self.meta.clone()
     }
+    fn source(&self) -> &str {
+        self.source
+    }
     fn display(&self) -> String {
         "MockTransanction".to_owned()
     }